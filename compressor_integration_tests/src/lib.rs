@@ -95,6 +95,21 @@ pub fn empty_database() {
     client.batch_execute(sql).unwrap();
 }
 
+/// Runs some SQL against the testing database
+///
+/// This is used to break the structure of the state group tables in ways that
+/// the other helper functions don't allow
+pub fn execute_sql_on_database(sql: &str) {
+    // connect to the database
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    let connector = MakeTlsConnector::new(builder.build());
+
+    let mut client = Client::connect(DB_URL, connector).unwrap();
+
+    client.batch_execute(sql).unwrap();
+}
+
 /// Safely escape the strings in sql queries
 struct PGEscape<'a>(pub &'a str);
 
@@ -315,6 +330,7 @@ pub fn clear_compressor_state() {
     let sql = r"
         TRUNCATE state_compressor_state;
         TRUNCATE state_compressor_progress;
+        TRUNCATE state_compressor_rooms_needing_attention;
         UPDATE state_compressor_total_progress SET lowest_uncompressed_group = 0;
     ";
 
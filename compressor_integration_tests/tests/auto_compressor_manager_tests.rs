@@ -14,7 +14,7 @@ use synapse_auto_compressor::{
     manager::{compress_chunks_of_database, run_compressor_on_room_chunk},
     state_saving::{connect_to_database, create_tables_if_needed},
 };
-use synapse_compress_state::Level;
+use synapse_compress_state::{CrossRoomPolicy, Level, VerifyMode};
 
 #[test]
 #[serial(db)]
@@ -45,11 +45,31 @@ fn run_compressor_on_room_chunk_works() {
     // 0  3\
     // 1  4 6
     // 2  5
-    run_compressor_on_room_chunk(DB_URL, "room1", 7, &default_levels).unwrap();
-
-    // compress the next 7 groups
-
-    run_compressor_on_room_chunk(DB_URL, "room1", 7, &default_levels).unwrap();
+    run_compressor_on_room_chunk(
+        DB_URL,
+        "room1",
+        7,
+        &default_levels,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+
+    // compress the next 7 groups (loading them over several connections,
+    // which shouldn't change the result)
+    run_compressor_on_room_chunk(
+        DB_URL,
+        "room1",
+        7,
+        &default_levels,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        3,
+    )
+    .unwrap();
 
     // This should have created the following structure in the database
     // i.e. groups 6 and 9 should have changed from before
@@ -97,7 +117,17 @@ fn compress_chunks_of_database_compresses_multiple_rooms() {
 
     // Compress 4 chunks of size 8.
     // The first two should compress room1 and the second two should compress room2
-    compress_chunks_of_database(DB_URL, 8, &default_levels, 4).unwrap();
+    compress_chunks_of_database(
+        DB_URL,
+        8,
+        &default_levels,
+        4,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
 
     // We are aiming for the following structure in the database for room1
     // i.e. groups 6 and 9 should have changed from initial map
@@ -175,12 +205,62 @@ fn compress_chunks_of_database_continues_where_it_left_off() {
     // Compress chunks of various sizes:
     //
     // These two should compress room1
-    compress_chunks_of_database(DB_URL, 8, &default_levels, 1).unwrap();
-    compress_chunks_of_database(DB_URL, 100, &default_levels, 1).unwrap();
+    compress_chunks_of_database(
+        DB_URL,
+        8,
+        &default_levels,
+        1,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+    compress_chunks_of_database(
+        DB_URL,
+        100,
+        &default_levels,
+        1,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
     // These three should compress room2
-    compress_chunks_of_database(DB_URL, 1, &default_levels, 2).unwrap();
-    compress_chunks_of_database(DB_URL, 5, &default_levels, 1).unwrap();
-    compress_chunks_of_database(DB_URL, 5, &default_levels, 1).unwrap();
+    compress_chunks_of_database(
+        DB_URL,
+        1,
+        &default_levels,
+        2,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+    compress_chunks_of_database(
+        DB_URL,
+        5,
+        &default_levels,
+        1,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+    compress_chunks_of_database(
+        DB_URL,
+        5,
+        &default_levels,
+        1,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
 
     // We are aiming for the following structure in the database for room1
     // i.e. groups 6 and 9 should have changed from initial map
@@ -1,8 +1,11 @@
-use compressor_integration_tests::{clear_compressor_state, setup_logger, DB_URL};
+use compressor_integration_tests::{
+    add_contents_to_database, clear_compressor_state, empty_database,
+    map_builder::line_segments_with_state, setup_logger, DB_URL,
+};
 use serial_test::serial;
 use synapse_auto_compressor::state_saving::{
-    connect_to_database, create_tables_if_needed, read_room_compressor_state,
-    write_room_compressor_state,
+    connect_to_database, create_tables_if_needed, get_next_room_to_compress,
+    mark_room_as_needing_attention, read_room_compressor_state, write_room_compressor_state,
 };
 use synapse_compress_state::Level;
 
@@ -27,3 +30,23 @@ fn write_then_read_state_gives_correct_results() {
     assert_eq!(written_info, read_info);
     assert_eq!(written_num, read_num);
 }
+
+#[test]
+#[serial(db)]
+fn rooms_needing_attention_are_skipped() {
+    setup_logger();
+    // room1 has the groups 0 to 13 and room2 has the groups 14 to 27
+    empty_database();
+    add_contents_to_database("room1", &line_segments_with_state(0, 13));
+    add_contents_to_database("room2", &line_segments_with_state(14, 27));
+
+    let mut client = connect_to_database(DB_URL).unwrap();
+    create_tables_if_needed(&mut client).unwrap();
+    clear_compressor_state();
+
+    mark_room_as_needing_attention(&mut client, "room1", 6, "testing").unwrap();
+
+    // room1 has the lowest uncompressed group, but should be skipped over
+    let next_room = get_next_room_to_compress(&mut client).unwrap();
+    assert_eq!(next_room, Some("room2".to_string()));
+}
@@ -1,8 +1,8 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, fs};
 
 use compressor_integration_tests::{
     add_contents_to_database, database_collapsed_states_match_map, database_structure_matches_map,
-    empty_database,
+    empty_database, execute_sql_on_database,
     map_builder::{
         compressed_3_3_from_0_to_13_with_state, line_segments_with_state, line_with_state,
         structure_from_edges_with_state,
@@ -10,7 +10,7 @@ use compressor_integration_tests::{
     setup_logger, DB_URL,
 };
 use serial_test::serial;
-use synapse_compress_state::{run, Config};
+use synapse_compress_state::{run, run_simulation, Config, ConfigBuilder};
 
 // Remember to add #[serial(db)] before any test that access the database.
 // Only one test with this annotation can run at once - preventing
@@ -67,6 +67,35 @@ fn run_succeeds_without_crashing() {
     run(config);
 }
 
+#[test]
+#[serial(db)]
+fn run_writes_html_report() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .level_sizes([3, 3])
+        .html_report(Some("./tests/tmp/run_writes_html_report.html"))
+        .build()
+        .unwrap();
+
+    run(config);
+
+    let report = fs::read_to_string("./tests/tmp/run_writes_html_report.html").unwrap();
+    assert!(report.contains("<h1>State compression report for room1</h1>"));
+    assert!(report.contains("<tr><td class=\"label\">Rows before compression</td><td>28</td></tr>"));
+}
+
 #[test]
 #[serial(db)]
 fn changes_commited_if_no_min_saved_rows() {
@@ -135,6 +164,105 @@ fn changes_commited_if_no_min_saved_rows() {
     assert!(database_structure_matches_map(&expected))
 }
 
+#[test]
+#[serial(db)]
+fn run_summarises_committed_changes() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .output_file(Some("./tests/tmp/run_summarises_committed_changes.sql"))
+        .level_sizes([3, 3])
+        .transactions(true)
+        .commit_changes(true)
+        .build()
+        .unwrap();
+
+    let summary = run(config);
+
+    assert_eq!(summary.min_state_group, Some(0));
+    assert_eq!(summary.max_state_group, Some(13));
+    assert_eq!(summary.original_rows - summary.compressed_rows, 11);
+    assert_eq!(summary.verified, Some(true));
+    assert!(summary.output_written);
+    assert!(summary.committed);
+    assert_eq!(summary.stopped_early, None);
+}
+
+#[test]
+#[serial(db)]
+fn changes_commited_using_copy_in_batches() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .level_sizes([3, 3])
+        .transactions(true)
+        .commit_changes(true)
+        // Write the changes two state groups at a time so that multiple batches are used
+        .copy_batch_size(Some(2))
+        // Check the state in the database once the batches have been committed
+        .verify_after_commit(true)
+        .verification_report(Some(
+            "./tests/tmp/changes_commited_using_copy_in_batches_report.jsonl",
+        ))
+        .build()
+        .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // This should have created the following structure in the database
+    // i.e. groups 6 and 9 should have changed from before
+    // N.B. this saves 11 rows
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Both the compressed state and the committed state should have been
+    // checked, with no mismatches found
+    let report =
+        fs::read_to_string("./tests/tmp/changes_commited_using_copy_in_batches_report.jsonl")
+            .unwrap();
+    let lines: Vec<&str> = report.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert!(lines[0].contains(r#""stage":"compressed""#));
+    assert!(lines[1].contains(r#""stage":"committed""#));
+    assert!(lines.iter().all(|line| line.contains(r#""mismatches":[]"#)));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
 #[test]
 #[serial(db)]
 fn changes_commited_if_min_saved_rows_exceeded() {
@@ -272,6 +400,43 @@ fn changes_not_commited_if_fewer_than_min_saved_rows() {
     assert!(database_structure_matches_map(&initial));
 }
 
+#[test]
+#[serial(db)]
+fn run_summary_says_why_changes_were_not_commited() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Compressing saves 11 rows
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .output_file(Some(
+            "./tests/tmp/run_summary_says_why_changes_were_not_commited.sql",
+        ))
+        .min_saved_rows(Some(12))
+        .level_sizes([3, 3])
+        .transactions(true)
+        .commit_changes(true)
+        .build()
+        .unwrap();
+
+    let summary = run(config);
+
+    assert!(!summary.committed);
+    assert_eq!(
+        summary.stopped_early.as_deref(),
+        Some("Only 11 rows would be saved by this compression")
+    );
+}
+
 #[test]
 #[should_panic(expected = "Error connecting to the database:")]
 fn run_panics_if_invalid_db_url() {
@@ -474,6 +639,126 @@ fn run_respects_groups_to_compress() {
     assert!(database_structure_matches_map(&expected))
 }
 
+#[test]
+#[serial(db)]
+fn run_respects_groups_to_compress_when_loading_over_multiple_connections() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // set up the config options
+    let output_file =
+        "./tests/tmp/run_respects_groups_to_compress_when_loading_over_multiple_connections.sql";
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .output_file(Some(output_file))
+        .min_state_group(Some(2))
+        .groups_to_compress(Some(9))
+        .level_sizes([3, 3])
+        .transactions(true)
+        .commit_changes(true)
+        // Load the groups over three connections, the result should be the same
+        // as when loading them over one
+        .load_connections(3)
+        .build()
+        .unwrap();
+
+    // Run the compressor with those settings
+    run(config);
+
+    // This should have created the following structure in the database
+    // as it should only compress from groups higher than 2 (non inclusive)
+    // and should only compress a total of 9 groups
+    // i.e. so only group 9 should have changed from before
+    // N.B. this saves 7 rows
+    //
+    // 0  3  6\    12
+    // 1  4  7  9  13
+    // 2  5  8 10
+    //         11
+    //
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 10),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+
+    let expected = structure_from_edges_with_state(expected_edges, 0, 13);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
+#[test]
+#[serial(db)]
+fn run_loads_room_ids_that_need_quoting() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+    let room_id = r"!it's\room:example.com";
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database(room_id, &initial);
+
+    let config = ConfigBuilder::new(DB_URL, room_id)
+        .output_file(Some("./tests/tmp/run_loads_room_ids_that_need_quoting.sql"))
+        .level_sizes([3, 3])
+        .transactions(true)
+        .commit_changes(true)
+        .load_connections(2)
+        .build()
+        .unwrap();
+
+    let summary = run(config);
+
+    assert_eq!(summary.state_groups, 14);
+    assert!(summary.committed);
+
+    // This should have created the following structure in the database
+    // i.e. groups 6 and 9 should have changed from before
+    //
+    // 0  3\      12
+    // 1  4 6\    13
+    // 2  5 7 9
+    //      8 10
+    //        11
+    let expected = compressed_3_3_from_0_to_13_with_state();
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // Check that the structure of the database matches the expected structure
+    assert!(database_structure_matches_map(&expected))
+}
+
 #[test]
 #[serial(db)]
 fn run_is_idempotent_when_run_on_whole_room() {
@@ -573,3 +858,95 @@ fn run_is_idempotent_when_run_on_whole_room() {
     // Check that the structure of the database still matches the expected structure
     assert!(database_structure_matches_map(&expected));
 }
+
+#[test]
+#[serial(db)]
+fn run_audit_reports_broken_state_group_tables() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Then break it in the following ways:
+    // - 1 and 2 are each other's predecessors
+    // - 4's predecessor doesn't exist
+    // - 6 is missing from the state_groups table
+    // - 10's predecessor is in another room
+    // - 12 has the same state row twice
+    execute_sql_on_database(
+        r"
+        UPDATE state_group_edges SET prev_state_group = 2 WHERE state_group = 1;
+        UPDATE state_group_edges SET prev_state_group = 1000 WHERE state_group = 4;
+        DELETE FROM state_groups WHERE id = 6;
+        INSERT INTO state_groups (id, room_id, event_id) VALUES (100, 'room2', 'left_blank');
+        UPDATE state_group_edges SET prev_state_group = 100 WHERE state_group = 10;
+        INSERT INTO state_groups_state (state_group, room_id, type, state_key, event_id)
+            SELECT state_group, room_id, type, state_key, event_id
+            FROM state_groups_state WHERE state_group = 12 AND type = 'node';
+        ",
+    );
+
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .output_file(Some(
+            "./tests/tmp/run_audit_reports_broken_state_group_tables.jsonl",
+        ))
+        .level_sizes([3, 3])
+        .transactions(true)
+        .audit(true)
+        .build()
+        .unwrap();
+
+    run(config);
+
+    let audit = fs::read_to_string("./tests/tmp/run_audit_reports_broken_state_group_tables.jsonl")
+        .unwrap();
+    let problems: Vec<&str> = audit.lines().collect();
+
+    assert_eq!(
+        problems,
+        vec![
+            r#"{"problem":"cycle","state_groups":[1,2]}"#,
+            r#"{"problem":"missing_predecessor","state_group":4,"prev_state_group":1000}"#,
+            r#"{"problem":"cross_room_edge","state_group":10,"room_id":"room1","prev_state_group":100,"prev_room_id":"room2"}"#,
+            r#"{"problem":"not_in_state_groups","state_group":6}"#,
+            r#"{"problem":"duplicate_state_rows","state_group":12,"type":"node","state_key":"is","count":2}"#,
+        ]
+    );
+}
+
+#[test]
+#[serial(db)]
+fn run_simulation_stops_early_if_no_state_groups_in_range() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2-3-4-5-6-7-8-9-10-11-12-13
+    let initial = line_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // There are no state groups after 13
+    let config = ConfigBuilder::new(DB_URL, "room1")
+        .min_state_group(Some(13))
+        .groups_to_compress(Some(10))
+        .simulate_level_sizes(vec![vec![3, 3]])
+        .build()
+        .unwrap();
+
+    let simulation = run_simulation(config);
+
+    assert_eq!(
+        simulation.stopped_early.as_deref(),
+        Some("No state groups found within this range")
+    );
+    assert!(simulation.results.is_empty());
+}
@@ -1,11 +1,21 @@
+use std::{collections::BTreeMap, thread, time::Duration};
+
 use compressor_integration_tests::{
     add_contents_to_database, database_collapsed_states_match_map, database_structure_matches_map,
-    empty_database,
-    map_builder::{compressed_3_3_from_0_to_13_with_state, line_segments_with_state},
+    empty_database, execute_sql_on_database,
+    map_builder::{
+        compressed_3_3_from_0_to_13_with_state, line_segments_with_state, line_with_state,
+        structure_from_edges_with_state,
+    },
     setup_logger, DB_URL,
 };
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use postgres::Client;
+use postgres_openssl::MakeTlsConnector;
 use serial_test::serial;
-use synapse_compress_state::{continue_run, Level};
+use synapse_compress_state::{
+    continue_run, ChunkStats, CrossRoomPolicy, Level, StateGroupEntry, VerifyMode,
+};
 
 // Tests the saving and continuing functionality
 // The compressor should produce the same results when run in one go
@@ -39,7 +49,18 @@ fn continue_run_called_twice_same_as_run() {
     let level_info = vec![Level::new(3), Level::new(3)];
 
     // Run the compressor with those settings
-    let chunk_stats_1 = continue_run(start, chunk_size, &db_url, &room_id, &level_info).unwrap();
+    let chunk_stats_1 = continue_run(
+        start,
+        chunk_size,
+        &db_url,
+        &room_id,
+        &level_info,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
 
     // Assert that it stopped at 6 (i.e. after the 7 groups 0...6)
     assert_eq!(chunk_stats_1.last_compressed_group, 6);
@@ -59,7 +80,18 @@ fn continue_run_called_twice_same_as_run() {
     let level_info = chunk_stats_1.new_level_info;
 
     // Run the compressor with those settings
-    let chunk_stats_2 = continue_run(start, chunk_size, &db_url, &room_id, &level_info).unwrap();
+    let chunk_stats_2 = continue_run(
+        start,
+        chunk_size,
+        &db_url,
+        &room_id,
+        &level_info,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
 
     // Assert that it stopped at 7
     assert_eq!(chunk_stats_2.last_compressed_group, 13);
@@ -81,3 +113,426 @@ fn continue_run_called_twice_same_as_run() {
     // Check that the structure of the database matches the expected structure
     assert!(database_structure_matches_map(&expected))
 }
+
+#[test]
+#[serial(db)]
+fn continue_run_verifies_state_in_database_after_commit() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    let db_url = DB_URL.to_string();
+    let room_id = "room1".to_string();
+
+    // compress all of the groups in 3,3 level sizes
+    let level_info = vec![Level::new(3), Level::new(3)];
+
+    // Run the compressor, checking the state in the database afterwards
+    let chunk_stats = continue_run(
+        None,
+        14,
+        &db_url,
+        &room_id,
+        &level_info,
+        true,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+
+    assert!(chunk_stats.commited);
+    assert_eq!(chunk_stats.verified, Some(true));
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_writes_groups_missing_from_state_groups_table() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    //
+    // Each group i has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let initial = line_segments_with_state(0, 13);
+
+    // Place this initial state into an empty database
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Group 9 only appears in the edges and state tables (which can happen
+    // "due to reasons"), so is loaded as the missing predecessor of 10
+    execute_sql_on_database("DELETE FROM state_groups WHERE id = 9");
+
+    let db_url = DB_URL.to_string();
+    let room_id = "room1".to_string();
+
+    // compress the groups after 2 (as groups missing from the state_groups
+    // table are only compressed if there is a starting group) in 3,3 level
+    // sizes
+    let level_info = vec![Level::new(3), Level::new(3)];
+
+    let chunk_stats = continue_run(
+        Some(2),
+        11,
+        &db_url,
+        &room_id,
+        &level_info,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+
+    // Group 9 hasn't changed since it was loaded, so the changes to it are
+    // written along with the rest
+    assert!(chunk_stats.commited);
+
+    // Group 9 needs to be back in the state_groups table to read its state
+    execute_sql_on_database(
+        "INSERT INTO state_groups (id, room_id, event_id) VALUES (9, 'room1', 'left_blank')",
+    );
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // This should have created the following structure in the database
+    // i.e. groups 9 and 12 should have changed from before
+    //
+    // 0  3  6    // 1  4  7 9    // 2  5  8 10 12
+    //         11 13
+    let expected_edges: BTreeMap<i64, i64> = vec![
+        (1, 0),
+        (2, 1),
+        (4, 3),
+        (5, 4),
+        (7, 6),
+        (8, 7),
+        (9, 6),
+        (10, 9),
+        (11, 10),
+        (12, 9),
+        (13, 12),
+    ]
+    .into_iter()
+    .collect();
+    let expected = structure_from_edges_with_state(expected_edges, 0, 13);
+
+    assert!(database_structure_matches_map(&expected));
+}
+
+/// Runs the compressor (with 3,3 level sizes) over the line segments
+/// 0-1-2 3-4-5 6-7-8 9-10-11 12-13, while `changed_group` is given the
+/// predecessor before it in a transaction that is only committed once the
+/// compressor has loaded the groups and is waiting to write over it
+fn continue_run_while_group_changes(changed_group: i64) -> ChunkStats {
+    let mut builder = SslConnector::builder(SslMethod::tls()).unwrap();
+    builder.set_verify(SslVerifyMode::NONE);
+    let connector = MakeTlsConnector::new(builder.build());
+
+    let mut client = Client::connect(DB_URL, connector.clone()).unwrap();
+    let mut watcher = Client::connect(DB_URL, connector).unwrap();
+
+    let mut transaction = client.transaction().unwrap();
+    transaction
+        .batch_execute(&format!(
+            "SELECT id FROM state_groups WHERE id = {0} FOR UPDATE; \
+             INSERT INTO state_group_edges (state_group, prev_state_group) VALUES ({0}, {1});",
+            changed_group,
+            changed_group - 1,
+        ))
+        .unwrap();
+
+    let compressor = thread::spawn(|| {
+        continue_run(
+            None,
+            14,
+            DB_URL,
+            "room1",
+            &[Level::new(3), Level::new(3)],
+            true,
+            VerifyMode::Full,
+            CrossRoomPolicy::Abort,
+            1,
+        )
+    });
+
+    let waiting_for_lock = r#"
+        SELECT COUNT(*) FROM pg_stat_activity
+        WHERE datname = current_database() AND wait_event_type = 'Lock'
+    "#;
+    while !compressor.is_finished()
+        && watcher
+            .query_one(waiting_for_lock, &[])
+            .unwrap()
+            .get::<_, i64>(0)
+            == 0
+    {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    transaction.commit().unwrap();
+
+    compressor.join().unwrap().unwrap()
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_writes_nothing_if_groups_change_after_loading() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Give group 6 (the first group that the compressor changes) a
+    // predecessor while the compressor is running
+    let chunk_stats = continue_run_while_group_changes(6);
+    assert!(!chunk_stats.commited);
+    assert_eq!(chunk_stats.groups_committed, 0);
+
+    // Nothing should have been written over the change
+    let mut expected = initial.clone();
+    expected.get_mut(&6).unwrap().prev_state_group = Some(5);
+    assert!(database_structure_matches_map(&expected));
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_reports_groups_committed_before_groups_change() {
+    setup_logger();
+    // This starts with the following structure
+    //
+    // 0-1-2 3-4-5 6-7-8 9-10-11 12-13
+    let initial = line_segments_with_state(0, 13);
+
+    empty_database();
+    add_contents_to_database("room1", &initial);
+
+    // Give group 9 (the second group that the compressor changes) a
+    // predecessor while the compressor is running
+    let chunk_stats = continue_run_while_group_changes(9);
+
+    // Group 6 was committed before 9 was found to have changed, so is
+    // reported (and checked) even though the chunk wasn't committed
+    assert!(!chunk_stats.commited);
+    assert_eq!(chunk_stats.groups_committed, 1);
+    assert_eq!(chunk_stats.verified, Some(true));
+
+    let mut expected = initial.clone();
+    expected.insert(6, compressed_3_3_from_0_to_13_with_state()[&6].clone());
+    expected.get_mut(&9).unwrap().prev_state_group = Some(8);
+    assert!(database_structure_matches_map(&expected));
+}
+
+/// Adds a room whose state depends on another room to the database, with
+/// `cross_room_group` (which must start one of the lines of room1) having the
+/// last group of the other room as its predecessor
+///
+/// Returns the state groups from both rooms (as they are in the database) and
+/// the state groups from just the other room
+fn add_room_with_predecessor_in_other_room(
+    cross_room_group: i64,
+) -> (
+    BTreeMap<i64, StateGroupEntry>,
+    BTreeMap<i64, StateGroupEntry>,
+) {
+    // This starts with the following structure (if `cross_room_group` is 6),
+    // where 20-21-22 are in room2 and the rest are in room1
+    //
+    // 0-1-2 3-4-5 20-21-22-6-7-8 9-10-11 12-13
+    //
+    // Each group i in room1 has state:
+    //     ('node','is',      i)
+    //     ('group',  j, 'seen') - for all j less than i
+    let mut room1 = line_segments_with_state(0, 13);
+    let room2 = line_with_state(20, 22);
+
+    empty_database();
+    add_contents_to_database("room1", &room1);
+    add_contents_to_database("room2", &room2);
+    execute_sql_on_database(&format!(
+        "INSERT INTO state_group_edges (state_group, prev_state_group) VALUES ({}, 22)",
+        cross_room_group
+    ));
+
+    room1.get_mut(&cross_room_group).unwrap().prev_state_group = Some(22);
+    room1.extend(room2.clone());
+
+    (room1, room2)
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_aborts_if_predecessor_in_other_room() {
+    setup_logger();
+    let (initial, _) = add_room_with_predecessor_in_other_room(6);
+
+    let level_info = vec![Level::new(3), Level::new(3)];
+
+    let chunk_stats = continue_run(
+        None,
+        14,
+        DB_URL,
+        "room1",
+        &level_info,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Abort,
+        1,
+    )
+    .unwrap();
+
+    assert!(!chunk_stats.commited);
+    assert_eq!(chunk_stats.cross_room.cross_room_groups, vec![6]);
+    assert_eq!(chunk_stats.new_level_info, level_info);
+
+    // Nothing should have changed in the database
+    assert!(database_structure_matches_map(&initial));
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_skips_groups_depending_on_other_room() {
+    setup_logger();
+    let (initial, _) = add_room_with_predecessor_in_other_room(6);
+
+    let level_info = vec![Level::new(3), Level::new(3)];
+
+    let chunk_stats = continue_run(
+        None,
+        14,
+        DB_URL,
+        "room1",
+        &level_info,
+        false,
+        VerifyMode::Full,
+        CrossRoomPolicy::Skip,
+        1,
+    )
+    .unwrap();
+
+    assert!(chunk_stats.commited);
+    assert_eq!(chunk_stats.cross_room.cross_room_groups, vec![6]);
+    assert_eq!(chunk_stats.cross_room.groups_skipped, 3);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // The groups from 6 to 8 (and the other room) should be left alone
+    let untouched: BTreeMap<i64, StateGroupEntry> = initial
+        .iter()
+        .filter(|(sg, _)| (6..=8).contains(*sg) || **sg >= 20)
+        .map(|(sg, entry)| (*sg, entry.clone()))
+        .collect();
+    assert!(database_structure_matches_map(&untouched));
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_treats_groups_with_predecessor_in_other_room_as_snapshots() {
+    setup_logger();
+    let (initial, room2) = add_room_with_predecessor_in_other_room(6);
+
+    let level_info = vec![Level::new(3), Level::new(3)];
+
+    let chunk_stats = continue_run(
+        None,
+        14,
+        DB_URL,
+        "room1",
+        &level_info,
+        true,
+        VerifyMode::Full,
+        CrossRoomPolicy::Snapshot,
+        1,
+    )
+    .unwrap();
+
+    assert!(chunk_stats.commited);
+    assert_eq!(chunk_stats.verified, Some(true));
+    assert_eq!(chunk_stats.cross_room.snapshot_groups, vec![6]);
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // The other room should be left alone
+    assert!(database_structure_matches_map(&room2));
+}
+
+#[test]
+#[serial(db)]
+fn continue_run_writes_snapshots_that_compressor_leaves_alone() {
+    setup_logger();
+    let (initial, room2) = add_room_with_predecessor_in_other_room(12);
+
+    // With 3,3 level sizes group 12 starts a new chain, so the compressor
+    // leaves its snapshot alone (it has no predecessor and holds its full
+    // state)
+    let level_info = vec![Level::new(3), Level::new(3)];
+
+    let chunk_stats = continue_run(
+        None,
+        14,
+        DB_URL,
+        "room1",
+        &level_info,
+        true,
+        VerifyMode::Full,
+        CrossRoomPolicy::Snapshot,
+        1,
+    )
+    .unwrap();
+
+    assert!(chunk_stats.commited);
+    assert_eq!(chunk_stats.verified, Some(true));
+    assert_eq!(chunk_stats.cross_room.snapshot_groups, vec![12]);
+
+    // The rows counted are the ones in the database, not the snapshot's
+    let loaded_rows: usize = initial
+        .iter()
+        .filter(|(sg, _)| **sg < 20)
+        .map(|(_, entry)| entry.state_map.len())
+        .sum();
+    assert_eq!(chunk_stats.original_num_rows, loaded_rows);
+
+    // The snapshot (group 12's delta on top of the state of 22, 21 and 20)
+    // should have been written to the database, replacing the edge to the
+    // other room
+    let mut snapshot = initial[&12].clone();
+    snapshot.prev_state_group = None;
+    for sg in [22, 21, 20] {
+        for ((t, s), e) in room2[&sg].state_map.iter() {
+            if snapshot.state_map.get(t, s).is_none() {
+                snapshot.state_map.insert(t, s, e.clone());
+            }
+        }
+    }
+    let expected = BTreeMap::from([(12, snapshot)]);
+    assert!(database_structure_matches_map(&expected));
+
+    // Check that the database still gives correct states for each group!
+    assert!(database_collapsed_states_match_map(&initial));
+
+    // The other room should be left alone
+    assert!(database_structure_matches_map(&room2));
+}
@@ -0,0 +1,340 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This checks the state group tables for structural problems.
+//!
+//! The compressor assumes that the predecessor links in `state_group_edges`
+//! form chains that end in a snapshot, that every predecessor exists and is
+//! in the same room, and that a state group has at most one entry for each
+//! (type, state_key). If any of these aren't true then the compressor can
+//! fail part way through a run (with a `Missing` panic when collapsing the
+//! state) or produce incorrect results.
+//!
+//! An audit reads the edges (and any duplicated state rows) for a room, or
+//! for the whole database, and reports every problem found.
+
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+};
+
+/// A row of the state_group_edges table, along with the rooms of the two
+/// state groups it links
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditEdge {
+    pub state_group: i64,
+    pub prev_state_group: i64,
+    // The room of the state group (None if it isn't in the state_groups table)
+    pub room_id: Option<String>,
+    // The room of the predecessor (None if it isn't in the state_groups table)
+    pub prev_room_id: Option<String>,
+    // Whether the predecessor has any rows in state_group_edges or
+    // state_groups_state (i.e. whether it exists at all)
+    pub prev_has_rows: bool,
+}
+
+/// An entry that appears more than once in the state of a single state group
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateStateRow {
+    pub state_group: i64,
+    pub etype: String,
+    pub state_key: String,
+    pub count: i64,
+}
+
+/// A structural problem found with the state group tables
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "problem", rename_all = "snake_case")]
+pub enum AuditProblem {
+    /// Following the predecessors from these groups leads back round to the
+    /// first one (the groups are listed in the order they are followed,
+    /// starting with the smallest id)
+    Cycle { state_groups: Vec<i64> },
+    /// The predecessor of the group doesn't exist anywhere in the database
+    MissingPredecessor {
+        state_group: i64,
+        prev_state_group: i64,
+    },
+    /// The predecessor of the group belongs to a different room
+    CrossRoomEdge {
+        state_group: i64,
+        room_id: String,
+        prev_state_group: i64,
+        prev_room_id: String,
+    },
+    /// The group has edges or state but isn't in the state_groups table
+    NotInStateGroups { state_group: i64 },
+    /// The group has more than one row in the state_group_edges table
+    MultiplePredecessors {
+        state_group: i64,
+        prev_state_groups: Vec<i64>,
+    },
+    /// The group has the same (type, state_key) in its delta more than once
+    DuplicateStateRows {
+        state_group: i64,
+        #[serde(rename = "type")]
+        etype: String,
+        state_key: String,
+        count: i64,
+    },
+    /// The chain of predecessors from the group back to a snapshot is longer
+    /// than the maximum depth (only the ends of chains are reported)
+    ChainTooLong { state_group: i64, depth: usize },
+}
+
+impl fmt::Display for AuditProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuditProblem::Cycle { state_groups } => {
+                write!(f, "Predecessors form a cycle: {:?}", state_groups)
+            }
+            AuditProblem::MissingPredecessor {
+                state_group,
+                prev_state_group,
+            } => write!(
+                f,
+                "State group {} has predecessor {} which does not exist",
+                state_group, prev_state_group
+            ),
+            AuditProblem::CrossRoomEdge {
+                state_group,
+                room_id,
+                prev_state_group,
+                prev_room_id,
+            } => write!(
+                f,
+                "State group {} in room {} has predecessor {} in room {}",
+                state_group, room_id, prev_state_group, prev_room_id
+            ),
+            AuditProblem::NotInStateGroups { state_group } => write!(
+                f,
+                "State group {} has edges or state but is not in the state_groups table",
+                state_group
+            ),
+            AuditProblem::MultiplePredecessors {
+                state_group,
+                prev_state_groups,
+            } => write!(
+                f,
+                "State group {} has multiple predecessors: {:?}",
+                state_group, prev_state_groups
+            ),
+            AuditProblem::DuplicateStateRows {
+                state_group,
+                etype,
+                state_key,
+                count,
+            } => write!(
+                f,
+                "State group {} has {} rows for ({}, {})",
+                state_group, count, etype, state_key
+            ),
+            AuditProblem::ChainTooLong { state_group, depth } => write!(
+                f,
+                "State group {} is {} predecessors away from a snapshot",
+                state_group, depth
+            ),
+        }
+    }
+}
+
+/// Works out all of the problems with the state group tables from the
+/// information loaded from the database
+///
+/// Returns the problems grouped by type, with each type sorted by state group
+///
+/// # Arguments
+///
+/// * `edges`           -   The rows of state_group_edges being checked
+/// * `duplicates`      -   The entries that appear more than once in the
+///                         state of a group
+/// * `max_chain_depth` -   The maximum allowed number of predecessors between
+///                         a group and a snapshot
+pub fn find_problems(
+    edges: &[AuditEdge],
+    duplicates: Vec<DuplicateStateRow>,
+    max_chain_depth: usize,
+) -> Vec<AuditProblem> {
+    let mut problems = Vec::new();
+
+    let mut prevs: BTreeMap<i64, Vec<i64>> = BTreeMap::new();
+    let mut not_in_state_groups: BTreeSet<i64> = BTreeSet::new();
+    let mut edge_problems = Vec::new();
+
+    for edge in edges {
+        prevs
+            .entry(edge.state_group)
+            .or_default()
+            .push(edge.prev_state_group);
+
+        if edge.room_id.is_none() {
+            not_in_state_groups.insert(edge.state_group);
+        }
+
+        match (&edge.room_id, &edge.prev_room_id) {
+            (_, None) if edge.prev_has_rows => {
+                not_in_state_groups.insert(edge.prev_state_group);
+            }
+            (_, None) => edge_problems.push(AuditProblem::MissingPredecessor {
+                state_group: edge.state_group,
+                prev_state_group: edge.prev_state_group,
+            }),
+            (Some(room_id), Some(prev_room_id)) if room_id != prev_room_id => {
+                edge_problems.push(AuditProblem::CrossRoomEdge {
+                    state_group: edge.state_group,
+                    room_id: room_id.clone(),
+                    prev_state_group: edge.prev_state_group,
+                    prev_room_id: prev_room_id.clone(),
+                })
+            }
+            _ => {}
+        }
+    }
+
+    // Only the first predecessor of each group is followed when looking at
+    // the chains (this is the same as the compressor when loading them)
+    let first_prevs: BTreeMap<i64, i64> = prevs.iter().map(|(sg, p)| (*sg, p[0])).collect();
+    problems.extend(find_chain_problems(&first_prevs, max_chain_depth));
+
+    edge_problems.sort_by_key(|problem| match problem {
+        AuditProblem::MissingPredecessor { state_group, .. }
+        | AuditProblem::CrossRoomEdge { state_group, .. } => *state_group,
+        _ => unreachable!("only edge problems are in this list"),
+    });
+    problems.extend(edge_problems);
+
+    problems.extend(
+        not_in_state_groups
+            .into_iter()
+            .map(|state_group| AuditProblem::NotInStateGroups { state_group }),
+    );
+
+    problems.extend(prevs.into_iter().filter(|(_, p)| p.len() > 1).map(
+        |(state_group, mut prev_state_groups)| {
+            prev_state_groups.sort_unstable();
+            AuditProblem::MultiplePredecessors {
+                state_group,
+                prev_state_groups,
+            }
+        },
+    ));
+
+    problems.extend(
+        duplicates
+            .into_iter()
+            .map(|row| AuditProblem::DuplicateStateRows {
+                state_group: row.state_group,
+                etype: row.etype,
+                state_key: row.state_key,
+                count: row.count,
+            }),
+    );
+
+    problems
+}
+
+/// Finds the cycles, and the chains that are longer than the maximum depth,
+/// in the predecessor links
+///
+/// # Arguments
+///
+/// * `prevs`           -   A map from each state group to its predecessor
+/// * `max_chain_depth` -   The maximum allowed number of predecessors between
+///                         a group and a snapshot
+pub fn find_chain_problems(
+    prevs: &BTreeMap<i64, i64>,
+    max_chain_depth: usize,
+) -> Vec<AuditProblem> {
+    enum Visit {
+        // The group is on the path currently being followed
+        InProgress,
+        // The number of predecessors between the group and a snapshot
+        Done(usize),
+    }
+
+    let mut problems = Vec::new();
+    let mut visits: BTreeMap<i64, Visit> = BTreeMap::new();
+
+    for start in prevs.keys() {
+        // Follow the predecessors until reaching a group that has already
+        // been worked out, a snapshot, or a group already on this path
+        let mut path = Vec::new();
+        let mut current = *start;
+
+        let mut depth = loop {
+            match visits.get(&current) {
+                Some(Visit::Done(depth)) => break *depth,
+                Some(Visit::InProgress) => {
+                    // The groups from the first time this one was reached
+                    // form a cycle. These are given a depth of 0 so that the
+                    // groups leading into the cycle still get a depth
+                    let position = path
+                        .iter()
+                        .position(|sg| *sg == current)
+                        .expect("in progress groups are on the path");
+                    let mut cycle = path.split_off(position);
+
+                    for sg in &cycle {
+                        visits.insert(*sg, Visit::Done(0));
+                    }
+
+                    let smallest = (0..cycle.len())
+                        .min_by_key(|i| cycle[*i])
+                        .expect("cycles are not empty");
+                    cycle.rotate_left(smallest);
+                    problems.push(AuditProblem::Cycle {
+                        state_groups: cycle,
+                    });
+
+                    break 0;
+                }
+                None => match prevs.get(&current) {
+                    Some(prev) => {
+                        visits.insert(current, Visit::InProgress);
+                        path.push(current);
+                        current = *prev;
+                    }
+                    // A group without a predecessor is a snapshot
+                    None => break 0,
+                },
+            }
+        };
+
+        for sg in path.into_iter().rev() {
+            depth += 1;
+            visits.insert(sg, Visit::Done(depth));
+        }
+    }
+
+    // Only report the groups at the ends of the chains (the ones that aren't
+    // the predecessor of anything) to avoid listing every group in a long chain
+    let has_successor: BTreeSet<i64> = prevs.values().copied().collect();
+
+    for (sg, visit) in &visits {
+        if let Visit::Done(depth) = visit {
+            if *depth > max_chain_depth && !has_successor.contains(sg) {
+                problems.push(AuditProblem::ChainTooLong {
+                    state_group: *sg,
+                    depth: *depth,
+                });
+            }
+        }
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod audit_tests;
@@ -0,0 +1,143 @@
+use crate::audit::{
+    find_chain_problems, find_problems, AuditEdge, AuditProblem, DuplicateStateRow,
+};
+use std::collections::BTreeMap;
+
+fn edge(state_group: i64, prev_state_group: i64) -> AuditEdge {
+    AuditEdge {
+        state_group,
+        prev_state_group,
+        room_id: Some("room1".to_string()),
+        prev_room_id: Some("room1".to_string()),
+        prev_has_rows: true,
+    }
+}
+
+#[test]
+fn find_problems_returns_nothing_for_healthy_chains() {
+    // This is the following structure
+    //
+    // 0-1-2
+    //  \
+    //   3-4
+    let edges = vec![edge(1, 0), edge(2, 1), edge(3, 0), edge(4, 3)];
+
+    assert_eq!(find_problems(&edges, Vec::new(), 10), Vec::new());
+}
+
+#[test]
+fn find_problems_reports_missing_and_cross_room_predecessors() {
+    let mut missing = edge(2, 1);
+    missing.prev_room_id = None;
+    missing.prev_has_rows = false;
+
+    let mut cross_room = edge(4, 3);
+    cross_room.prev_room_id = Some("room2".to_string());
+
+    let edges = vec![cross_room, missing];
+
+    assert_eq!(
+        find_problems(&edges, Vec::new(), 10),
+        vec![
+            AuditProblem::MissingPredecessor {
+                state_group: 2,
+                prev_state_group: 1,
+            },
+            AuditProblem::CrossRoomEdge {
+                state_group: 4,
+                room_id: "room1".to_string(),
+                prev_state_group: 3,
+                prev_room_id: "room2".to_string(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn find_problems_reports_groups_not_in_state_groups() {
+    // 1 is only in the edges table (but does have rows) and 3 isn't in the
+    // state_groups table either
+    let mut prev_not_in_table = edge(2, 1);
+    prev_not_in_table.prev_room_id = None;
+
+    let mut not_in_table = edge(3, 2);
+    not_in_table.room_id = None;
+
+    let edges = vec![prev_not_in_table, not_in_table];
+
+    assert_eq!(
+        find_problems(&edges, Vec::new(), 10),
+        vec![
+            AuditProblem::NotInStateGroups { state_group: 1 },
+            AuditProblem::NotInStateGroups { state_group: 3 },
+        ]
+    );
+}
+
+#[test]
+fn find_problems_reports_multiple_predecessors_and_duplicates() {
+    let edges = vec![edge(1, 0), edge(2, 1), edge(2, 0)];
+    let duplicates = vec![DuplicateStateRow {
+        state_group: 1,
+        etype: "m.room.member".to_string(),
+        state_key: "@user:server".to_string(),
+        count: 2,
+    }];
+
+    assert_eq!(
+        find_problems(&edges, duplicates, 10),
+        vec![
+            AuditProblem::MultiplePredecessors {
+                state_group: 2,
+                prev_state_groups: vec![0, 1],
+            },
+            AuditProblem::DuplicateStateRows {
+                state_group: 1,
+                etype: "m.room.member".to_string(),
+                state_key: "@user:server".to_string(),
+                count: 2,
+            },
+        ]
+    );
+}
+
+#[test]
+fn find_chain_problems_reports_cycles_once() {
+    // This is the following structure, where 3 points back to 1 and 5 leads
+    // into the cycle
+    //
+    // 1-2-3
+    // ^   |
+    // \---/
+    //   \
+    //    5
+    let prevs: BTreeMap<i64, i64> = [(1, 3), (2, 1), (3, 2), (5, 2)].into_iter().collect();
+
+    assert_eq!(
+        find_chain_problems(&prevs, 10),
+        vec![AuditProblem::Cycle {
+            state_groups: vec![1, 3, 2],
+        }]
+    );
+}
+
+#[test]
+fn find_chain_problems_reports_ends_of_long_chains() {
+    // This is the following structure
+    //
+    // 0-1-2-3-4-5
+    //      \
+    //       6
+    let mut prevs: BTreeMap<i64, i64> = (1..=5).map(|i| (i, i - 1)).collect();
+    prevs.insert(6, 2);
+
+    assert_eq!(
+        find_chain_problems(&prevs, 3),
+        vec![AuditProblem::ChainTooLong {
+            state_group: 5,
+            depth: 5,
+        }]
+    );
+
+    assert_eq!(find_chain_problems(&prevs, 5), Vec::new());
+}
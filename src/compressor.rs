@@ -29,11 +29,16 @@
 //! ```
 
 use indicatif::{ProgressBar, ProgressStyle};
+use serde::Serialize;
 use state_map::StateMap;
-use std::{collections::BTreeMap, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    time::{Duration, Instant},
+};
 use string_cache::DefaultAtom as Atom;
 
-use super::{collapse_state_maps, StateGroupEntry};
+use super::{chain_depths, collapse_state_maps, StateGroupEntry};
 
 /// Holds information about a particular level.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -109,8 +114,45 @@ impl Level {
     }
 }
 
+/// The number of values in each of the buckets 0, 1, 2-3, 4-7, 8-15 and so
+/// on, keyed by the smallest value in the bucket (so that a few very large
+/// values don't squash everything else into one bucket)
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Histogram(pub BTreeMap<usize, usize>);
+
+impl Histogram {
+    /// Counts the values into their buckets
+    pub fn from_values(values: impl IntoIterator<Item = usize>) -> Histogram {
+        let mut histogram = Histogram::default();
+        for value in values {
+            let bucket = value.checked_ilog2().map_or(0, |b| 1 << b);
+            *histogram.0.entry(bucket).or_default() += 1;
+        }
+        histogram
+    }
+
+    /// The label for the bucket starting at the given value, e.g. "4-7"
+    pub fn bucket_label(bucket: usize) -> String {
+        match bucket {
+            0 | 1 => bucket.to_string(),
+            _ => format!("{}-{}", bucket, bucket * 2 - 1),
+        }
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets: Vec<String> = self
+            .0
+            .iter()
+            .map(|(bucket, count)| format!("{}: {}", Histogram::bucket_label(*bucket), count))
+            .collect();
+        write!(f, "{}", buckets.join(", "))
+    }
+}
+
 /// Keeps track of some statistics of a compression run.
-#[derive(Default)]
+#[derive(Default, Debug, Clone, Serialize)]
 pub struct Stats {
     /// How many state groups we couldn't find a delta for, despite trying.
     pub resets_no_suitable_prev: usize,
@@ -119,6 +161,72 @@ pub struct Stats {
     pub resets_no_suitable_prev_size: usize,
     /// How many state groups we have changed.
     pub state_groups_changed: usize,
+    /// How many of the state groups were snapshots (had no predecessor)
+    /// before compression.
+    pub snapshots_before: usize,
+    /// How many of the state groups were snapshots after compression.
+    pub snapshots_after: usize,
+    /// How many predecessors the state groups were away from a snapshot
+    /// before compression.
+    pub chain_depths_before: Histogram,
+    /// How many predecessors the state groups were away from a snapshot
+    /// after compression.
+    pub chain_depths_after: Histogram,
+    /// How many rows the state groups had before compression.
+    pub delta_sizes_before: Histogram,
+    /// How many rows the state groups had after compression.
+    pub delta_sizes_after: Histogram,
+    /// How many state groups were placed in each level.
+    pub groups_per_level: Vec<usize>,
+    /// How many times the head of a level wasn't a valid base for a delta,
+    /// so that `get_delta` had to walk up the tree to find one.
+    pub delta_walks: usize,
+    /// The total number of groups walked up by those walks.
+    pub delta_walk_steps: usize,
+    /// The largest number of groups walked up by one of those walks.
+    pub delta_walk_max_steps: usize,
+    /// How long was spent loading the state groups (set by the caller).
+    pub load_time: Duration,
+    /// How long was spent compressing the state groups.
+    pub compress_time: Duration,
+    /// How long was spent verifying the state groups (set by the caller).
+    pub verify_time: Duration,
+    /// How long was spent committing the changes (set by the caller).
+    pub commit_time: Duration,
+}
+
+impl Stats {
+    /// Records a walk up the tree to find a base for a delta
+    fn record_delta_walk(&mut self, steps: usize) {
+        if steps > 0 {
+            self.delta_walks += 1;
+            self.delta_walk_steps += steps;
+            self.delta_walk_max_steps = self.delta_walk_max_steps.max(steps);
+        }
+    }
+
+    /// Records the shape of the state groups before and after compression
+    fn record_structure(
+        &mut self,
+        before: &BTreeMap<i64, StateGroupEntry>,
+        after: &BTreeMap<i64, StateGroupEntry>,
+    ) {
+        let snapshots = |map: &BTreeMap<i64, StateGroupEntry>| {
+            map.values()
+                .filter(|entry| entry.prev_state_group.is_none())
+                .count()
+        };
+        self.snapshots_before = snapshots(before);
+        self.snapshots_after = snapshots(after);
+
+        self.chain_depths_before = Histogram::from_values(chain_depths(before).into_values());
+        self.chain_depths_after = Histogram::from_values(chain_depths(after).into_values());
+
+        self.delta_sizes_before =
+            Histogram::from_values(before.values().map(|entry| entry.state_map.len()));
+        self.delta_sizes_after =
+            Histogram::from_values(after.values().map(|entry| entry.state_map.len()));
+    }
 }
 
 /// Attempts to compress a set of state deltas using the given level sizes.
@@ -127,6 +235,8 @@ pub struct Compressor<'a> {
     pub new_state_group_map: BTreeMap<i64, StateGroupEntry>,
     levels: Vec<Level>,
     pub stats: Stats,
+    /// The index of the level that each compressed state group was placed in
+    pub group_levels: BTreeMap<i64, usize>,
 }
 
 impl<'a> Compressor<'a> {
@@ -140,6 +250,7 @@ impl<'a> Compressor<'a> {
             new_state_group_map: BTreeMap::new(),
             levels: level_sizes.iter().map(|size| Level::new(*size)).collect(),
             stats: Stats::default(),
+            group_levels: BTreeMap::new(),
         };
 
         compressor.create_new_tree();
@@ -164,6 +275,7 @@ impl<'a> Compressor<'a> {
             new_state_group_map: BTreeMap::new(),
             levels,
             stats: Stats::default(),
+            group_levels: BTreeMap::new(),
         };
 
         compressor.create_new_tree();
@@ -194,6 +306,9 @@ impl<'a> Compressor<'a> {
         pb.set_message("state groups");
         pb.enable_steady_tick(Duration::from_millis(100));
 
+        let start = Instant::now();
+        self.stats.groups_per_level = vec![0; self.levels.len()];
+
         for (&state_group, entry) in self.original_state_map {
             // Check whether this entry is in_range or is just present in the map due to being
             // a predecessor of a group that IS in_range for compression
@@ -213,7 +328,11 @@ impl<'a> Compressor<'a> {
                 continue;
             }
             let mut prev_state_group = None;
-            for level in &mut self.levels {
+            // If every level is full then the group starts a new chain in all
+            // of them, and so is counted as being in the top level
+            let mut level_index = 0;
+            for (index, level) in self.levels.iter_mut().enumerate() {
+                level_index = index;
                 if level.has_space() {
                     prev_state_group = level.get_head();
                     level.update(state_group, true);
@@ -222,6 +341,10 @@ impl<'a> Compressor<'a> {
                     level.update(state_group, false);
                 }
             }
+            self.group_levels.insert(state_group, level_index);
+            if let Some(count) = self.stats.groups_per_level.get_mut(level_index) {
+                *count += 1;
+            }
 
             let (delta, prev_state_group) = if entry.prev_state_group == prev_state_group {
                 (entry.state_map.clone(), prev_state_group)
@@ -243,6 +366,10 @@ impl<'a> Compressor<'a> {
         }
 
         pb.finish();
+
+        self.stats
+            .record_structure(self.original_state_map, &self.new_state_group_map);
+        self.stats.compress_time = start.elapsed();
     }
 
     /// Attempts to calculate the delta between two state groups.
@@ -265,6 +392,7 @@ impl<'a> Compressor<'a> {
         // This is a loop to go through to find the first prev_sg which can be
         // a valid base for the state group.
         let mut prev_state_map;
+        let mut steps = 0;
         'outer: loop {
             prev_state_map = collapse_state_maps(self.original_state_map, prev_sg);
             for (t, s) in prev_state_map.keys() {
@@ -274,11 +402,13 @@ impl<'a> Compressor<'a> {
                     // better base.
                     if let Some(psg) = self.new_state_group_map[&prev_sg].prev_state_group {
                         prev_sg = psg;
+                        steps += 1;
                         continue 'outer;
                     }
 
                     // Couldn't find a new base, so we give up and just persist
                     // a full state group here.
+                    self.stats.record_delta_walk(steps);
                     self.stats.resets_no_suitable_prev += 1;
                     self.stats.resets_no_suitable_prev_size += state_map.len();
 
@@ -289,6 +419,8 @@ impl<'a> Compressor<'a> {
             break;
         }
 
+        self.stats.record_delta_walk(steps);
+
         // We've found a valid base, now we just need to calculate the delta.
         let mut delta_map = StateMap::new();
 
@@ -62,6 +62,14 @@ fn compress_creates_correct_compressor() {
             sg,
         );
     }
+
+    // The groups starting a chain in the bottom level are in the level above
+    // (with 12 starting a new chain in both levels)
+    let expected_levels: BTreeMap<i64, usize> = (0i64..=13i64)
+        .map(|sg| (sg, usize::from(sg % 3 == 0 && sg != 0)))
+        .collect();
+
+    assert_eq!(compressor.group_levels, expected_levels);
 }
 
 #[test]
@@ -114,6 +122,7 @@ fn create_new_tree_does_nothing_if_already_compressed() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
 
     compressor.create_new_tree();
@@ -149,6 +158,7 @@ fn create_new_tree_respects_levels() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
     compressor.create_new_tree();
 
@@ -211,6 +221,7 @@ fn create_new_tree_panics_if_run_twice() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
     compressor.create_new_tree();
     compressor.create_new_tree();
@@ -242,6 +253,7 @@ fn create_new_tree_respects_all_not_in_range() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
     compressor.create_new_tree();
 
@@ -304,6 +316,7 @@ fn create_new_tree_respects_some_not_in_range() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
     compressor.create_new_tree();
 
@@ -387,6 +400,7 @@ fn create_new_tree_deals_with_impossible_preds() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
     compressor.create_new_tree();
 
@@ -666,6 +680,7 @@ fn get_delta_returns_snapshot_if_no_prev_possible() {
         new_state_group_map: new_map,
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
 
     // make the levels how they would be after 0,1,2,3 added
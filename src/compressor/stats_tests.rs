@@ -1,5 +1,5 @@
 use crate::{
-    compressor::{Compressor, Level, Stats},
+    compressor::{Compressor, Histogram, Level, Stats},
     StateGroupEntry,
 };
 use state_map::StateMap;
@@ -31,6 +31,7 @@ fn stats_correct_when_no_resets() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
 
     // This should create the following structure
@@ -48,6 +49,26 @@ fn stats_correct_when_no_resets() {
 
     // Groups 3,6,9,12 should be the only ones changed
     assert_eq!(compressor.stats.state_groups_changed, 4);
+
+    // 0 was the only snapshot, now 3 and 12 are as well
+    assert_eq!(compressor.stats.snapshots_before, 1);
+    assert_eq!(compressor.stats.snapshots_after, 3);
+
+    // The chain depths went from 0...13 to at most 4
+    assert_eq!(
+        compressor.stats.chain_depths_before,
+        Histogram(BTreeMap::from([(0, 1), (1, 1), (2, 2), (4, 4), (8, 6)]))
+    );
+    assert_eq!(
+        compressor.stats.chain_depths_after,
+        Histogram(BTreeMap::from([(0, 3), (1, 4), (2, 6), (4, 1)]))
+    );
+
+    // 3,6,9,12 are in the top level and the rest in the bottom
+    assert_eq!(compressor.stats.groups_per_level, vec![10, 4]);
+
+    // There is no state so every head is a valid base
+    assert_eq!(compressor.stats.delta_walks, 0);
 }
 
 #[test]
@@ -88,6 +109,7 @@ fn stats_correct_when_some_resets() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
 
     // This should create the following structure
@@ -111,6 +133,10 @@ fn stats_correct_when_some_resets() {
 
     // groups 3,4,6,9,12 are the only ones changed
     assert_eq!(compressor.stats.state_groups_changed, 5);
+
+    // For both 4 and 6 the head 3 wasn't a valid base, but as 3 is a
+    // snapshot there was nothing to walk up to
+    assert_eq!(compressor.stats.delta_walks, 0);
 }
 
 #[test]
@@ -163,6 +189,7 @@ fn stats_correct_if_no_changes() {
         new_state_group_map: BTreeMap::new(),
         levels: vec![Level::new(3), Level::new(3)],
         stats: Stats::default(),
+        group_levels: BTreeMap::new(),
     };
 
     // This should create the following structure (i.e. no change)
@@ -179,3 +206,64 @@ fn stats_correct_if_no_changes() {
     assert_eq!(compressor.stats.resets_no_suitable_prev_size, 0);
     assert_eq!(compressor.stats.state_groups_changed, 0);
 }
+
+#[test]
+fn stats_count_walks_up_the_tree() {
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+
+    // This starts with three snapshots with the following state
+    //
+    // 0: a
+    // 1: a, b
+    // 2: a, c
+    for (i, keys) in [(0, vec!["a"]), (1, vec!["a", "b"]), (2, vec!["a", "c"])] {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: None,
+            state_map: StateMap::new(),
+        };
+        for key in keys {
+            entry.state_map.insert("node", key, "seen".into());
+        }
+        initial.insert(i, entry);
+    }
+
+    let mut compressor = Compressor {
+        original_state_map: &initial,
+        new_state_group_map: BTreeMap::new(),
+        levels: vec![Level::new(3)],
+        stats: Stats::default(),
+        group_levels: BTreeMap::new(),
+    };
+
+    // This should create the chain 0-1 with 2 pointing at 0, since the head 1
+    // has b (which 2 doesn't) and so the compressor walks up to 0
+    compressor.create_new_tree();
+
+    assert_eq!(compressor.new_state_group_map[&2].prev_state_group, Some(0));
+    assert_eq!(compressor.stats.delta_walks, 1);
+    assert_eq!(compressor.stats.delta_walk_steps, 1);
+    assert_eq!(compressor.stats.delta_walk_max_steps, 1);
+    assert_eq!(compressor.stats.resets_no_suitable_prev, 0);
+}
+
+#[test]
+fn histogram_counts_values_in_powers_of_two() {
+    let histogram = Histogram::from_values([0, 1, 2, 3, 4, 7, 8, 100]);
+
+    assert_eq!(
+        histogram,
+        Histogram(BTreeMap::from([
+            (0, 1),
+            (1, 1),
+            (2, 2),
+            (4, 2),
+            (8, 1),
+            (64, 1)
+        ]))
+    );
+    assert_eq!(
+        histogram.to_string(),
+        "0: 1, 1: 1, 2-3: 2, 4-7: 2, 8-15: 1, 64-127: 1"
+    );
+}
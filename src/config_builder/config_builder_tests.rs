@@ -0,0 +1,251 @@
+use crate::{
+    ConfigBuilder, CrossRoomPolicy, GraphFormat, LevelSizes, OutputFormat, RoomSelection,
+    VerifyMode,
+};
+use std::path::Path;
+
+const DB_URL: &str = "postgresql://homeserver.com/synapse";
+
+/// Builds the config, returning the error it gives
+fn error(builder: ConfigBuilder) -> String {
+    match builder.build() {
+        Ok(_) => panic!("The config should not have built"),
+        Err(e) => e,
+    }
+}
+
+#[test]
+fn defaults_match_command_line_tool() {
+    let config = ConfigBuilder::new(DB_URL, "!room").build().unwrap();
+
+    assert_eq!(config.db_url, DB_URL);
+    assert_eq!(config.room_id, "!room");
+    assert!(config.output_file.is_none());
+    assert_eq!(config.output_format, OutputFormat::Sql);
+    assert_eq!(config.level_sizes, LevelSizes(vec![100, 50, 25]));
+    assert_eq!(config.load_connections, 1);
+    assert!(!config.transactions);
+    assert!(!config.commit_changes);
+    assert!(config.verify);
+    assert_eq!(config.verify_mode, VerifyMode::Full);
+    assert_eq!(config.cross_room_policy, CrossRoomPolicy::Abort);
+    assert_eq!(config.graph_format, GraphFormat::Csv);
+    assert!(config.room_ids.is_none());
+}
+
+#[test]
+fn setters_are_used_by_config() {
+    let config = ConfigBuilder::new(DB_URL, "!room")
+        .output_file(Some("/tmp/config_builder_tests_output.sql"))
+        .output_format(OutputFormat::JsonLines)
+        .min_state_group(Some(3225))
+        .groups_to_compress(Some(970))
+        .min_saved_rows(Some(500))
+        .max_state_group(Some(3453))
+        .level_sizes([128, 64, 32])
+        .load_connections(4)
+        .transactions(true)
+        .commit_changes(true)
+        .copy_batch_size(Some(500))
+        .verify_mode(VerifyMode::Sampled(20))
+        .verify_after_commit(true)
+        .cross_room_policy(CrossRoomPolicy::Snapshot)
+        .json_summary(true)
+        .build()
+        .unwrap();
+
+    assert!(config.output_file.is_some());
+    assert_eq!(config.output_format, OutputFormat::JsonLines);
+    assert_eq!(config.min_state_group, Some(3225));
+    assert_eq!(config.groups_to_compress, Some(970));
+    assert_eq!(config.min_saved_rows, Some(500));
+    assert_eq!(config.max_state_group, Some(3453));
+    assert_eq!(config.level_sizes, LevelSizes(vec![128, 64, 32]));
+    assert_eq!(config.load_connections, 4);
+    assert!(config.transactions);
+    assert!(config.commit_changes);
+    assert_eq!(config.copy_batch_size, Some(500));
+    assert_eq!(config.verify_mode, VerifyMode::Sampled(20));
+    assert!(config.verify_after_commit);
+    assert_eq!(config.cross_room_policy, CrossRoomPolicy::Snapshot);
+    assert!(config.json_summary());
+}
+
+#[test]
+fn room_selection_processes_given_room_too() {
+    let selection = RoomSelection {
+        min_state_rows: Some(1000),
+        alias_pattern: None,
+    };
+
+    let config = ConfigBuilder::new(DB_URL, "!room")
+        .room_selection(selection.clone())
+        .build()
+        .unwrap();
+    assert_eq!(config.room_ids, Some(vec!["!room".to_string()]));
+
+    let config = ConfigBuilder::new(DB_URL, "")
+        .room_selection(selection)
+        .build()
+        .unwrap();
+    assert_eq!(config.room_ids, Some(Vec::new()));
+
+    let config = ConfigBuilder::new(DB_URL, "")
+        .room_ids(vec!["!a".to_string(), "!b".to_string()])
+        .build()
+        .unwrap();
+    assert_eq!(config.room_id, "!a");
+    assert_eq!(config.room_ids.as_ref().map(Vec::len), Some(2));
+}
+
+#[test]
+fn rooms_must_be_given() {
+    assert_eq!(
+        error(ConfigBuilder::new("", "!room")),
+        "db_url must not be empty"
+    );
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "")),
+        "room_id must not be empty (unless auditing or estimating every room)"
+    );
+
+    // Every room can be audited or estimated
+    assert!(ConfigBuilder::new(DB_URL, "").audit(true).build().is_ok());
+    assert!(ConfigBuilder::new(DB_URL, "")
+        .estimate(true)
+        .build()
+        .is_ok());
+
+    assert_eq!(
+        error(
+            ConfigBuilder::new(DB_URL, "")
+                .room_ids(vec!["!a".to_string(), "!b".to_string()])
+                .audit(true)
+        ),
+        "Only one room can be audited at a time"
+    );
+}
+
+#[test]
+fn range_of_groups_is_for_a_single_room() {
+    let selection = RoomSelection {
+        min_state_rows: Some(1000),
+        alias_pattern: None,
+    };
+
+    assert_eq!(
+        error(
+            ConfigBuilder::new(DB_URL, "")
+                .room_ids(vec!["!a".to_string(), "!b".to_string()])
+                .max_state_group(Some(100))
+        ),
+        "min_state_group, groups_to_compress and max_state_group can only be used with a single room"
+    );
+    assert!(ConfigBuilder::new(DB_URL, "!room")
+        .room_selection(selection.clone())
+        .groups_to_compress(Some(100))
+        .build()
+        .is_err());
+
+    // Estimating uses them for the sample of each room
+    assert!(ConfigBuilder::new(DB_URL, "")
+        .room_selection(selection)
+        .min_state_group(Some(100))
+        .groups_to_compress(Some(100))
+        .estimate(true)
+        .build()
+        .is_ok());
+}
+
+#[test]
+fn settings_must_make_sense_together() {
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").transactions(true)),
+        "transactions needs an output_file to write the transactions to"
+    );
+    // Committing always uses transactions
+    assert!(ConfigBuilder::new(DB_URL, "!room")
+        .transactions(true)
+        .commit_changes(true)
+        .build()
+        .is_ok());
+
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").min_state_group(Some(100))),
+        "min_state_group needs groups_to_compress to say how many groups to compress"
+    );
+    // Estimating uses a sample size if no number of groups is given
+    assert!(ConfigBuilder::new(DB_URL, "!room")
+        .min_state_group(Some(100))
+        .estimate(true)
+        .build()
+        .is_ok());
+
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").verify_after_commit(true)),
+        "verify_after_commit needs commit_changes to be set"
+    );
+    assert_eq!(
+        error(
+            ConfigBuilder::new(DB_URL, "!room")
+                .commit_changes(true)
+                .copy_batch_size(Some(0))
+        ),
+        "copy_batch_size must be greater than 0"
+    );
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").load_connections(0)),
+        "load_connections must be greater than 0"
+    );
+}
+
+#[test]
+fn level_sizes_must_not_be_empty() {
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").level_sizes(Vec::new())),
+        "level_sizes must contain at least one level"
+    );
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").level_sizes([100, 0])),
+        "level_sizes must all be greater than 0"
+    );
+    assert_eq!(
+        error(ConfigBuilder::new(DB_URL, "!room").simulate_level_sizes(vec![vec![10], vec![]])),
+        "The level sizes to simulate must contain at least one level"
+    );
+}
+
+#[test]
+fn nothing_is_created_if_settings_are_invalid() {
+    let path = "/tmp/config_builder_tests_not_created.sql";
+    let _ = std::fs::remove_file(path);
+
+    let e = error(
+        ConfigBuilder::new(DB_URL, "!room")
+            .output_file(Some(path))
+            .load_connections(0),
+    );
+
+    assert_eq!(e, "load_connections must be greater than 0");
+    assert!(!Path::new(path).exists());
+}
+
+#[test]
+fn files_are_not_created_by_building() {
+    let path = "/tmp/config_builder_tests_created_by_run.sql";
+    std::fs::write(path, "left over from before").unwrap();
+
+    let config = ConfigBuilder::new(DB_URL, "!room")
+        .output_file(Some(path))
+        .verification_report(Some(path))
+        .html_report(Some(path))
+        .build()
+        .unwrap();
+
+    // The file is only truncated once the config is run
+    assert_eq!(config.output_file.as_deref(), Some(Path::new(path)));
+    assert_eq!(
+        std::fs::read_to_string(path).unwrap(),
+        "left over from before"
+    );
+}
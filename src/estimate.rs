@@ -0,0 +1,181 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This estimates how much the compressor would save across the database,
+//! without writing anything, so that it can be decided whether compressing
+//! is worth it and which rooms to start with.
+//!
+//! Rather than loading every state group, only a bounded sample of each
+//! room's groups (the first ones after the starting group) is compressed.
+//! The rows and bytes saved in the sample are then scaled up by the number
+//! of rows the whole room has in `state_groups_state` (where the bytes are
+//! worked out from the strings in each row, see `row_size`).
+
+use serde::Serialize;
+use std::fmt::Write;
+
+use crate::row_size::format_bytes;
+
+/// The estimated savings for a single room
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct RoomEstimate {
+    pub room_id: String,
+    // The number of rows the room has in state_groups_state
+    pub state_rows: usize,
+    // The number of state groups that were compressed as a sample (including
+    // the predecessors that had to be loaded with them)
+    pub sampled_groups: usize,
+    // The number of rows of the sample before and after compression
+    pub sampled_rows: usize,
+    pub sampled_compressed_rows: usize,
+    // The estimated size of the rows of the sample before and after
+    // compression
+    pub sampled_bytes: usize,
+    pub sampled_compressed_bytes: usize,
+    // The rows and bytes that compressing the whole room would be expected
+    // to save
+    pub estimated_rows_saved: usize,
+    pub estimated_bytes_saved: u64,
+    // Why the room couldn't be estimated (None if it was)
+    pub skipped: Option<String>,
+}
+
+impl RoomEstimate {
+    /// Scales the rows and bytes saved in the sample up to the whole room
+    pub fn extrapolate(&mut self) {
+        let rows_saved = self
+            .sampled_rows
+            .saturating_sub(self.sampled_compressed_rows);
+        let bytes_saved = self
+            .sampled_bytes
+            .saturating_sub(self.sampled_compressed_bytes);
+
+        // If the sample has as many rows as the room then it is the whole
+        // room, so nothing needs scaling (it can have more, as predecessors
+        // before the starting group are loaded along with the sample)
+        let scale = if self.sampled_rows == 0 {
+            0.
+        } else if self.sampled_rows >= self.state_rows {
+            1.
+        } else {
+            self.state_rows as f64 / self.sampled_rows as f64
+        };
+
+        self.estimated_rows_saved = (rows_saved as f64 * scale).round() as usize;
+        self.estimated_bytes_saved = (bytes_saved as f64 * scale).round() as u64;
+    }
+}
+
+/// The estimated savings for the database
+#[derive(Serialize, Default, Debug, Clone, PartialEq)]
+pub struct Estimate {
+    // The rooms that were estimated, ranked by the number of rows they would
+    // save (most first)
+    pub rooms: Vec<RoomEstimate>,
+    // The totals over every room
+    pub state_rows: usize,
+    pub estimated_rows_saved: usize,
+    pub estimated_bytes_saved: u64,
+}
+
+impl Estimate {
+    /// Builds the estimate from the rooms (in any order)
+    pub fn new(mut rooms: Vec<RoomEstimate>) -> Estimate {
+        for room in &mut rooms {
+            room.extrapolate();
+        }
+
+        // The rooms that couldn't be estimated go at the bottom
+        rooms.sort_by(|a, b| {
+            a.skipped
+                .is_some()
+                .cmp(&b.skipped.is_some())
+                .then(b.estimated_rows_saved.cmp(&a.estimated_rows_saved))
+                .then(b.estimated_bytes_saved.cmp(&a.estimated_bytes_saved))
+                .then(a.room_id.cmp(&b.room_id))
+        });
+
+        Estimate {
+            state_rows: rooms.iter().map(|room| room.state_rows).sum(),
+            estimated_rows_saved: rooms.iter().map(|room| room.estimated_rows_saved).sum(),
+            estimated_bytes_saved: rooms.iter().map(|room| room.estimated_bytes_saved).sum(),
+            rooms,
+        }
+    }
+
+    /// Formats the ranking of the rooms as a table, followed by the totals
+    pub fn table(&self) -> String {
+        let room_width = self
+            .rooms
+            .iter()
+            .map(|room| room.room_id.len())
+            .chain(["Room".len()])
+            .max()
+            .unwrap_or_default();
+
+        let mut table = String::new();
+        writeln!(
+            table,
+            "{:>4}  {:<room_width$}  {:>12}  {:>12}  {:>6}  {:>12}",
+            "Rank", "Room", "Rows", "Rows saved", "Saved", "Bytes saved",
+        )
+        .unwrap();
+
+        for (i, room) in self.rooms.iter().enumerate() {
+            match &room.skipped {
+                Some(reason) => writeln!(
+                    table,
+                    "{:>4}  {:<room_width$}  {:>12}  skipped: {}",
+                    "-", room.room_id, room.state_rows, reason,
+                ),
+                None => writeln!(
+                    table,
+                    "{:>4}  {:<room_width$}  {:>12}  {:>12}  {:>6}  {:>12}",
+                    i + 1,
+                    room.room_id,
+                    room.state_rows,
+                    room.estimated_rows_saved,
+                    percentage(room.estimated_rows_saved, room.state_rows),
+                    format_bytes(room.estimated_bytes_saved),
+                ),
+            }
+            .unwrap();
+        }
+
+        writeln!(
+            table,
+            "{:>4}  {:<room_width$}  {:>12}  {:>12}  {:>6}  {:>12}",
+            "",
+            "Total",
+            self.state_rows,
+            self.estimated_rows_saved,
+            percentage(self.estimated_rows_saved, self.state_rows),
+            format_bytes(self.estimated_bytes_saved),
+        )
+        .unwrap();
+
+        table
+    }
+}
+
+/// Formats `part` as a percentage of `whole`
+fn percentage(part: usize, whole: usize) -> String {
+    if whole == 0 {
+        return "-".to_string();
+    }
+    format!("{:.1}%", part as f64 / whole as f64 * 100.)
+}
+
+#[cfg(test)]
+mod estimate_tests;
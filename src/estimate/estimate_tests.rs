@@ -0,0 +1,85 @@
+use crate::estimate::{Estimate, RoomEstimate};
+
+#[test]
+fn extrapolate_scales_sample_up_to_room() {
+    // A quarter of the sample is saved, so a quarter of the room should be
+    let mut estimate = RoomEstimate {
+        room_id: "room1".to_string(),
+        state_rows: 1000,
+        sampled_groups: 10,
+        sampled_rows: 100,
+        sampled_compressed_rows: 75,
+        sampled_bytes: 10000,
+        sampled_compressed_bytes: 7500,
+        ..RoomEstimate::default()
+    };
+    estimate.extrapolate();
+    assert_eq!(estimate.estimated_rows_saved, 250);
+    assert_eq!(estimate.estimated_bytes_saved, 25000);
+
+    // If the sample is the whole room then nothing needs scaling
+    estimate.state_rows = 100;
+    estimate.sampled_rows = 120;
+    estimate.sampled_compressed_rows = 60;
+    estimate.extrapolate();
+    assert_eq!(estimate.estimated_rows_saved, 60);
+
+    // Compressions that would add rows don't save anything
+    estimate.state_rows = 1000;
+    estimate.sampled_rows = 100;
+    estimate.sampled_compressed_rows = 150;
+    estimate.extrapolate();
+    assert_eq!(estimate.estimated_rows_saved, 0);
+
+    estimate.sampled_rows = 0;
+    estimate.sampled_compressed_rows = 0;
+    estimate.extrapolate();
+    assert_eq!(estimate.estimated_rows_saved, 0);
+}
+
+#[test]
+fn estimate_ranks_rooms_by_rows_saved() {
+    // Half of each sample is saved, with every row being 100 bytes
+    let mut rooms: Vec<RoomEstimate> = [("small", 100), ("large", 10000), ("medium", 1000)]
+        .into_iter()
+        .map(|(room_id, state_rows)| RoomEstimate {
+            room_id: room_id.to_string(),
+            state_rows,
+            sampled_groups: 10,
+            sampled_rows: 100,
+            sampled_compressed_rows: 50,
+            sampled_bytes: 10000,
+            sampled_compressed_bytes: 5000,
+            ..RoomEstimate::default()
+        })
+        .collect();
+    rooms.insert(
+        0,
+        RoomEstimate {
+            room_id: "skipped".to_string(),
+            state_rows: 5000,
+            skipped: Some("State depends on another room".to_string()),
+            ..RoomEstimate::default()
+        },
+    );
+
+    let estimate = Estimate::new(rooms);
+
+    let order: Vec<&str> = estimate
+        .rooms
+        .iter()
+        .map(|room| room.room_id.as_str())
+        .collect();
+    assert_eq!(order, ["large", "medium", "small", "skipped"]);
+
+    assert_eq!(estimate.state_rows, 16100);
+    assert_eq!(estimate.estimated_rows_saved, 5550);
+    assert_eq!(estimate.estimated_bytes_saved, 555000);
+
+    let table = estimate.table();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert!(lines[1].contains("large") && lines[1].contains("50.0%"));
+    assert!(lines[4].contains("skipped: State depends on another room"));
+    assert!(lines[5].contains("Total") && lines[5].contains("542.0 KiB"));
+}
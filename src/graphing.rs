@@ -1,9 +1,97 @@
-use std::{collections::BTreeMap, fs::File, io::Write};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+    str::FromStr,
+};
 
-use super::StateGroupEntry;
+use super::{chain_depths, collapse_state_maps, compressor::Level, StateGroupEntry};
 
 type Graph = BTreeMap<i64, StateGroupEntry>;
 
+/// The format that the before and after graphs are written in
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum GraphFormat {
+    /// Semicolon separated edges and nodes files (for loading into Gephi)
+    Csv,
+    /// A Graphviz DOT file (for rendering with `dot`)
+    Dot,
+    /// A GraphML file
+    GraphMl,
+}
+
+impl FromStr for GraphFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(GraphFormat::Csv),
+            "dot" => Ok(GraphFormat::Dot),
+            "graphml" => Ok(GraphFormat::GraphMl),
+            _ => Err("Graph format must be one of 'csv', 'dot' or 'graphml'"),
+        }
+    }
+}
+
+/// The information about a state group that is shown on its node
+#[derive(Debug, PartialEq, Eq)]
+struct NodeInfo {
+    // The number of rows the group has in the state_groups_state table
+    rows: usize,
+    // Whether the group has no predecessor
+    snapshot: bool,
+    // The number of predecessors between the group and a snapshot
+    depth: usize,
+    // Whether the group was in the range being compressed
+    in_range: bool,
+    // Whether the compressor changed the group's predecessor or delta
+    changed: bool,
+    // The index of the level the compressor placed the group in (if any)
+    level: Option<usize>,
+    // Whether the group is at the head of one of the compressor's levels
+    level_head: bool,
+    // The number of entries in the group's full (collapsed) state
+    state_size: usize,
+}
+
+/// Works out the information to show on the node of each state group in a graph
+///
+/// # Arguments
+///
+/// * `groups`          - A map from state group ids to StateGroupEntries
+/// * `changed`         - The state groups that the compressor changed
+/// * `group_levels`    - The index of the level that each compressed group was
+///                       placed in
+/// * `level_heads`     - The state groups at the heads of the compressor's levels
+/// * `state_sizes`     - The size of the full state of each group
+fn describe_nodes(
+    groups: &Graph,
+    changed: &BTreeSet<i64>,
+    group_levels: &BTreeMap<i64, usize>,
+    level_heads: &BTreeSet<i64>,
+    state_sizes: &BTreeMap<i64, usize>,
+) -> BTreeMap<i64, NodeInfo> {
+    let depths = chain_depths(groups);
+
+    groups
+        .iter()
+        .map(|(sg, entry)| {
+            let info = NodeInfo {
+                rows: entry.state_map.len(),
+                snapshot: entry.prev_state_group.is_none(),
+                depth: depths[sg],
+                in_range: entry.in_range,
+                changed: changed.contains(sg),
+                level: group_levels.get(sg).copied(),
+                level_head: level_heads.contains(sg),
+                state_size: state_sizes.get(sg).copied().unwrap_or_default(),
+            };
+            (*sg, info)
+        })
+        .collect()
+}
+
 /// Outputs information from a state group graph into an edges file and a node file
 ///
 /// These can be loaded into something like Gephi to visualise the graphs
@@ -11,18 +99,34 @@ type Graph = BTreeMap<i64, StateGroupEntry>;
 /// # Arguments
 ///
 /// * `groups`          - A map from state group ids to StateGroupEntries
+/// * `nodes`           - The information to show about each state group
 /// * `edges_output`    - The file to output the predecessor link information to
 /// * `nodes_output`    - The file to output the state group information to
-fn output_csv(groups: &Graph, edges_output: &mut File, nodes_output: &mut File) {
+fn output_csv(
+    groups: &Graph,
+    nodes: &BTreeMap<i64, NodeInfo>,
+    edges_output: &mut impl Write,
+    nodes_output: &mut impl Write,
+) {
     // The line A;B in the edges file means:
     //      That state group A has predecessor B
     writeln!(edges_output, "Source;Target",).unwrap();
 
-    // The line A;B;C;"B" in the nodes file means:
+    // The line A;B;C;"B";D;E;F;G;H;I in the nodes file means:
     //      The state group id is A
     //      This state group has B rows in the state_groups_state table
     //      If C is true then A has no predecessor
-    writeln!(nodes_output, "Id;Rows;Root;Label",).unwrap();
+    //      A is D predecessors away from a snapshot
+    //      If E is true then A was in the range being compressed
+    //      If F is true then the compressor changed A
+    //      The compressor placed A in level G (empty if it wasn't compressed)
+    //      If H is true then A is the head of one of the compressor's levels
+    //      The full state of A has I entries
+    writeln!(
+        nodes_output,
+        "Id;Rows;Root;Label;Depth;InRange;Changed;Level;LevelHead;StateSize",
+    )
+    .unwrap();
 
     for (source, entry) in groups {
         // If the group has a predecessor then write an edge in the edges file
@@ -31,40 +135,416 @@ fn output_csv(groups: &Graph, edges_output: &mut File, nodes_output: &mut File)
         }
 
         // Write the state group's information to the nodes file
+        let node = &nodes[source];
         writeln!(
             nodes_output,
-            "{};{};{};\"{}\"",
+            "{};{};{};\"{}\";{};{};{};{};{};{}",
             source,
-            entry.state_map.len(),
-            entry.prev_state_group.is_none(),
-            entry.state_map.len(),
+            node.rows,
+            node.snapshot,
+            node.rows,
+            node.depth,
+            node.in_range,
+            node.changed,
+            node.level.map(|l| l.to_string()).unwrap_or_default(),
+            node.level_head,
+            node.state_size,
         )
         .unwrap();
     }
 }
 
+/// Outputs information from a state group graph as a Graphviz DOT digraph
+///
+/// Each state group is a node labelled with its id and number of rows, with an
+/// edge pointing to its predecessor. Snapshots (groups with no predecessor) are
+/// filled in and level heads are drawn with a thick red outline. The rest of
+/// the information about each group is added as extra node attributes
+///
+/// # Arguments
+///
+/// * `groups`      - A map from state group ids to StateGroupEntries
+/// * `nodes`       - The information to show about each state group
+/// * `output`      - Where to write the graph to
+fn output_dot(groups: &Graph, nodes: &BTreeMap<i64, NodeInfo>, output: &mut impl Write) {
+    writeln!(output, "digraph state_groups {{").unwrap();
+    writeln!(output, "    node [shape=box];").unwrap();
+
+    for (source, node) in nodes {
+        let mut attributes = vec![format!("label=\"{}\\n{} rows\"", source, node.rows)];
+        if node.snapshot {
+            attributes.push("style=filled, fillcolor=lightblue".to_string());
+        }
+        if node.level_head {
+            attributes.push("color=red, penwidth=3".to_string());
+        }
+        attributes.push(format!(
+            "depth={}, in_range={}, changed={}, state_size={}",
+            node.depth, node.in_range, node.changed, node.state_size
+        ));
+        if let Some(level) = node.level {
+            attributes.push(format!("level={}", level));
+        }
+
+        writeln!(output, "    {} [{}];", source, attributes.join(", ")).unwrap();
+    }
+
+    for (source, entry) in groups {
+        if let Some(target) = entry.prev_state_group {
+            writeln!(output, "    {} -> {};", source, target).unwrap();
+        }
+    }
+
+    writeln!(output, "}}").unwrap();
+}
+
+/// Outputs information from a state group graph as a GraphML graph
+///
+/// Each state group is a node with the information about it as attributes,
+/// with an edge pointing to its predecessor
+///
+/// # Arguments
+///
+/// * `groups`      - A map from state group ids to StateGroupEntries
+/// * `nodes`       - The information to show about each state group
+/// * `output`      - Where to write the graph to
+fn output_graphml(groups: &Graph, nodes: &BTreeMap<i64, NodeInfo>, output: &mut impl Write) {
+    writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        output,
+        r#"<graphml xmlns="http://graphml.graphdrawing.org/xmlns">"#
+    )
+    .unwrap();
+
+    let keys = [
+        ("rows", "int"),
+        ("snapshot", "boolean"),
+        ("depth", "int"),
+        ("in_range", "boolean"),
+        ("changed", "boolean"),
+        ("level", "int"),
+        ("level_head", "boolean"),
+        ("state_size", "int"),
+    ];
+    for (key, key_type) in keys {
+        writeln!(
+            output,
+            r#"  <key id="{key}" for="node" attr.name="{key}" attr.type="{key_type}"/>"#
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        output,
+        r#"  <graph id="state_groups" edgedefault="directed">"#
+    )
+    .unwrap();
+
+    for (source, node) in nodes {
+        let mut data = vec![
+            ("rows", node.rows.to_string()),
+            ("snapshot", node.snapshot.to_string()),
+            ("depth", node.depth.to_string()),
+            ("in_range", node.in_range.to_string()),
+            ("changed", node.changed.to_string()),
+        ];
+        // Groups that weren't compressed have no level
+        if let Some(level) = node.level {
+            data.push(("level", level.to_string()));
+        }
+        data.push(("level_head", node.level_head.to_string()));
+        data.push(("state_size", node.state_size.to_string()));
+
+        write!(output, r#"    <node id="{}">"#, source).unwrap();
+        for (key, value) in data {
+            write!(output, r#"<data key="{}">{}</data>"#, key, value).unwrap();
+        }
+        writeln!(output, "</node>").unwrap();
+    }
+
+    for (source, entry) in groups {
+        if let Some(target) = entry.prev_state_group {
+            writeln!(
+                output,
+                r#"    <edge source="{}" target="{}"/>"#,
+                source, target
+            )
+            .unwrap();
+        }
+    }
+
+    writeln!(output, "  </graph>").unwrap();
+    writeln!(output, "</graphml>").unwrap();
+}
+
+/// Works out the path to write a graph file to
+///
+/// Without a prefix this is just the file's name (in the current directory).
+/// Otherwise it is the prefix followed by the room id (with any characters that
+/// aren't safe to use in file names replaced) and the file's name
+///
+/// # Arguments
+///
+/// * `prefix`  - The prefix (which can include directories) to write the files to
+/// * `room_id` - The room that the graphs are of
+/// * `name`    - The name of the file
+fn graph_path(prefix: Option<&str>, room_id: &str, name: &str) -> PathBuf {
+    match prefix {
+        None => PathBuf::from(name),
+        Some(prefix) => {
+            let room_id: String = room_id
+                .chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect();
+            PathBuf::from(format!("{}{}_{}", prefix, room_id, name))
+        }
+    }
+}
+
 /// Outputs information from two state group graph into files
 ///
 /// These can be loaded into something like Gephi to visualise the graphs
-/// before and after the compressor is run
+/// before and after the compressor is run (or rendered with Graphviz if the
+/// format is DOT)
 ///
 /// # Arguments
 ///
-/// * `before`      - A map from state group ids to StateGroupEntries
-///                   the information from this map goes into before_edges.csv
-///                   and before_nodes.csv
-/// * `after`       - A map from state group ids to StateGroupEntries
-///                   the information from this map goes into after_edges.csv
-///                   and after_nodes.csv
-pub fn make_graphs(before: &Graph, after: &Graph) {
-    // Open all the files to output to
-    let mut before_edges_file = File::create("before_edges.csv").unwrap();
-    let mut before_nodes_file = File::create("before_nodes.csv").unwrap();
-    let mut after_edges_file = File::create("after_edges.csv").unwrap();
-    let mut after_nodes_file = File::create("after_nodes.csv").unwrap();
-
-    // Write before's information to before_edges and before_nodes
-    output_csv(before, &mut before_edges_file, &mut before_nodes_file);
-    // Write afters's information to after_edges and after_nodes
-    output_csv(after, &mut after_edges_file, &mut after_nodes_file);
+/// * `before`          - A map from state group ids to StateGroupEntries
+///                       the information from this map goes into before_edges.csv
+///                       and before_nodes.csv (or before.dot or before.graphml)
+/// * `after`           - A map from state group ids to StateGroupEntries
+///                       the information from this map goes into after_edges.csv
+///                       and after_nodes.csv (or after.dot or after.graphml)
+/// * `levels`          - The compressor's levels after it was run. Their heads
+///                       are highlighted in the after graph
+/// * `group_levels`    - The index of the level that each compressed group was
+///                       placed in
+/// * `format`          - The format to write the graphs in
+/// * `prefix`          - If given, the files are written to this prefix followed
+///                       by the room id (instead of the current directory)
+/// * `room_id`         - The room that the graphs are of
+pub fn make_graphs(
+    before: &Graph,
+    after: &Graph,
+    levels: &[Level],
+    group_levels: &BTreeMap<i64, usize>,
+    format: GraphFormat,
+    prefix: Option<&str>,
+    room_id: &str,
+) {
+    let level_heads: BTreeSet<i64> = levels.iter().filter_map(|l| l.get_head()).collect();
+
+    let changed: BTreeSet<i64> = after
+        .iter()
+        .filter(|(sg, entry)| before.get(sg) != Some(entry))
+        .map(|(sg, _)| *sg)
+        .collect();
+
+    // The compressor doesn't change the state of any of the groups so this is
+    // the same for both graphs
+    let state_sizes: BTreeMap<i64, usize> = before
+        .keys()
+        .map(|sg| (*sg, collapse_state_maps(before, *sg).len()))
+        .collect();
+
+    // The level heads only mean anything in the after graph
+    let before_nodes = describe_nodes(
+        before,
+        &changed,
+        group_levels,
+        &BTreeSet::new(),
+        &state_sizes,
+    );
+    let after_nodes = describe_nodes(after, &changed, group_levels, &level_heads, &state_sizes);
+
+    let create = |name: &str| {
+        let path = graph_path(prefix, room_id, name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        File::create(path).unwrap()
+    };
+
+    match format {
+        GraphFormat::Csv => {
+            // Write before's information to before_edges and before_nodes
+            output_csv(
+                before,
+                &before_nodes,
+                &mut create("before_edges.csv"),
+                &mut create("before_nodes.csv"),
+            );
+            // Write afters's information to after_edges and after_nodes
+            output_csv(
+                after,
+                &after_nodes,
+                &mut create("after_edges.csv"),
+                &mut create("after_nodes.csv"),
+            );
+        }
+        GraphFormat::Dot => {
+            output_dot(before, &before_nodes, &mut create("before.dot"));
+            output_dot(after, &after_nodes, &mut create("after.dot"));
+        }
+        GraphFormat::GraphMl => {
+            output_graphml(before, &before_nodes, &mut create("before.graphml"));
+            output_graphml(after, &after_nodes, &mut create("after.graphml"));
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_graph() -> Graph {
+    // This is the structure 0-1 2, where 0 and 2 are snapshots and 2 isn't in
+    // range
+    let mut graph = super::test_utils::structure_from_edges(&[(0, None), (1, Some(0)), (2, None)]);
+    graph.get_mut(&2).unwrap().in_range = false;
+    graph
+}
+
+#[cfg(test)]
+fn test_nodes(level_head: i64) -> BTreeMap<i64, NodeInfo> {
+    let graph = test_graph();
+    let state_sizes = graph
+        .keys()
+        .map(|sg| (*sg, collapse_state_maps(&graph, *sg).len()))
+        .collect();
+
+    describe_nodes(
+        &graph,
+        &BTreeSet::from([1]),
+        &BTreeMap::from([(0, 1), (1, 0)]),
+        &BTreeSet::from([level_head]),
+        &state_sizes,
+    )
+}
+
+#[test]
+fn test_describe_nodes() {
+    let nodes = test_nodes(1);
+
+    assert_eq!(
+        nodes[&1],
+        NodeInfo {
+            rows: 1,
+            snapshot: false,
+            depth: 1,
+            in_range: true,
+            changed: true,
+            level: Some(0),
+            level_head: true,
+            state_size: 2,
+        }
+    );
+    assert_eq!(
+        nodes[&2],
+        NodeInfo {
+            rows: 1,
+            snapshot: true,
+            depth: 0,
+            in_range: false,
+            changed: false,
+            level: None,
+            level_head: false,
+            state_size: 1,
+        }
+    );
+}
+
+#[test]
+fn test_chain_depths() {
+    let mut graph = test_graph();
+    graph.get_mut(&2).unwrap().prev_state_group = Some(1);
+
+    assert_eq!(
+        chain_depths(&graph),
+        BTreeMap::from([(0, 0), (1, 1), (2, 2)])
+    );
+}
+
+#[test]
+fn test_output_csv() {
+    let mut edges = Vec::new();
+    let mut nodes = Vec::new();
+    output_csv(&test_graph(), &test_nodes(1), &mut edges, &mut nodes);
+
+    assert_eq!(String::from_utf8(edges).unwrap(), "Source;Target\n1;0\n");
+    assert_eq!(
+        String::from_utf8(nodes).unwrap(),
+        concat!(
+            "Id;Rows;Root;Label;Depth;InRange;Changed;Level;LevelHead;StateSize\n",
+            "0;1;true;\"1\";0;true;false;1;false;1\n",
+            "1;1;false;\"1\";1;true;true;0;true;2\n",
+            "2;1;true;\"1\";0;false;false;;false;1\n",
+        )
+    );
+}
+
+#[test]
+fn test_output_dot() {
+    let mut output = Vec::new();
+    output_dot(&test_graph(), &test_nodes(1), &mut output);
+
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        concat!(
+            "digraph state_groups {\n",
+            "    node [shape=box];\n",
+            "    0 [label=\"0\\n1 rows\", style=filled, fillcolor=lightblue, depth=0, in_range=true, changed=false, state_size=1, level=1];\n",
+            "    1 [label=\"1\\n1 rows\", color=red, penwidth=3, depth=1, in_range=true, changed=true, state_size=2, level=0];\n",
+            "    2 [label=\"2\\n1 rows\", style=filled, fillcolor=lightblue, depth=0, in_range=false, changed=false, state_size=1];\n",
+            "    1 -> 0;\n",
+            "}\n",
+        )
+    );
+}
+
+#[test]
+fn test_output_graphml() {
+    let mut output = Vec::new();
+    output_graphml(&test_graph(), &test_nodes(2), &mut output);
+    let output = String::from_utf8(output).unwrap();
+
+    assert!(output.contains(concat!(
+        r#"<node id="1"><data key="rows">1</data><data key="snapshot">false</data>"#,
+        r#"<data key="depth">1</data><data key="in_range">true</data><data key="changed">true</data>"#,
+        r#"<data key="level">0</data><data key="level_head">false</data><data key="state_size">2</data></node>"#
+    )));
+    assert!(output.contains(concat!(
+        r#"<node id="2"><data key="rows">1</data><data key="snapshot">true</data>"#,
+        r#"<data key="depth">0</data><data key="in_range">false</data><data key="changed">false</data>"#,
+        r#"<data key="level_head">true</data><data key="state_size">1</data></node>"#
+    )));
+    assert!(output.contains(r#"<edge source="1" target="0"/>"#));
+    assert_eq!(output.matches("<edge ").count(), 1);
+    assert!(output.ends_with("</graphml>\n"));
+}
+
+#[test]
+fn test_graph_path() {
+    assert_eq!(
+        graph_path(None, "!room:example.com", "before.dot"),
+        PathBuf::from("before.dot")
+    );
+    assert_eq!(
+        graph_path(Some("graphs/run1-"), "!room:example.com", "before.dot"),
+        PathBuf::from("graphs/run1-_room_example.com_before.dot")
+    );
+}
+
+#[test]
+fn test_graph_format_from_str() {
+    assert_eq!(GraphFormat::from_str("csv").unwrap(), GraphFormat::Csv);
+    assert_eq!(GraphFormat::from_str("dot").unwrap(), GraphFormat::Dot);
+    assert_eq!(
+        GraphFormat::from_str("graphml").unwrap(),
+        GraphFormat::GraphMl
+    );
+    assert!(GraphFormat::from_str("png").is_err());
 }
@@ -41,5 +41,21 @@ fn main() {
         env_logger::Builder::from_env("RUST_LOG").init();
     }
 
-    comp_state::run(comp_state::Config::parse_arguments());
+    let config = comp_state::Config::parse_arguments();
+    let json_summary = config.json_summary();
+
+    let summary = if config.estimate() {
+        serde_json::to_string(&comp_state::run_estimate(config))
+    } else if config.simulate() {
+        serde_json::to_string(&comp_state::run_simulation(config))
+    } else if config.multiple_rooms() {
+        serde_json::to_string(&comp_state::run_rooms(config))
+    } else {
+        serde_json::to_string(&comp_state::run(config))
+    }
+    .expect("Serializing a summary cannot fail");
+
+    if json_summary {
+        println!("{}", summary);
+    }
 }
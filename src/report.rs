@@ -0,0 +1,464 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This writes a single, self-contained HTML file summarising a compression
+//! run, so that it can be attached to a ticket or sent to someone.
+//!
+//! The report includes the number of rows before and after, the compressor's
+//! statistics, histograms of the chain depths and delta sizes, the largest
+//! snapshots and a drawing of the before and after graphs (which can be
+//! clicked on to see the information about a state group). Everything is
+//! inlined so the file can be opened without a network connection.
+
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    io::{self, Write},
+};
+
+use super::{
+    chain_depths,
+    compressor::{Histogram, Stats},
+    CrossRoomStats, Level, StateGroupEntry,
+};
+
+type Graph = BTreeMap<i64, StateGroupEntry>;
+
+/// The number of snapshots listed in the largest snapshots table
+const LARGEST_SNAPSHOTS: usize = 10;
+
+/// Graphs with more state groups than this aren't drawn (the browser would
+/// struggle and the file would be huge)
+const MAX_DRAWN_GROUPS: usize = 5000;
+
+/// Everything about a compression run that goes in the report
+pub struct RunReport<'a> {
+    pub room_id: &'a str,
+    // The state groups as they were loaded from the database
+    pub before: &'a Graph,
+    // The state groups after compression
+    pub after: &'a Graph,
+    pub stats: &'a Stats,
+    pub cross_room: &'a CrossRoomStats,
+    // The compressor's levels after it was run
+    pub levels: &'a [Level],
+}
+
+/// A state group as it is drawn in the report's graphs
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct DrawnGroup {
+    id: i64,
+    prev: Option<i64>,
+    rows: usize,
+    depth: usize,
+    changed: bool,
+    level_head: bool,
+}
+
+/// A row of a histogram comparing the before and after graphs
+#[derive(Debug, PartialEq, Eq)]
+struct HistogramBucket {
+    label: String,
+    before: usize,
+    after: usize,
+}
+
+/// Lines up the buckets of the before and after histograms, filling in any
+/// empty buckets between the smallest and largest
+///
+/// # Arguments
+///
+/// * `before`  - The histogram from before compression
+/// * `after`   - The histogram from after compression
+fn histogram(before: &Histogram, after: &Histogram) -> Vec<HistogramBucket> {
+    let buckets = before.0.keys().chain(after.0.keys());
+    let (Some(first), Some(last)) = (buckets.clone().min(), buckets.max()) else {
+        return Vec::new();
+    };
+
+    let mut rows = Vec::new();
+    let mut bucket = *first;
+    while bucket <= *last {
+        rows.push(HistogramBucket {
+            label: Histogram::bucket_label(bucket),
+            before: before.0.get(&bucket).copied().unwrap_or_default(),
+            after: after.0.get(&bucket).copied().unwrap_or_default(),
+        });
+        bucket = if bucket == 0 { 1 } else { bucket * 2 };
+    }
+    rows
+}
+
+/// Escapes text so that it can be put in HTML
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; margin: 2em; color: #222; }
+table { border-collapse: collapse; margin-bottom: 1.5em; }
+th, td { border: 1px solid #ccc; padding: 0.2em 0.6em; text-align: right; }
+th { background: #f0f0f0; }
+td.label { text-align: left; }
+.bar { display: inline-block; height: 0.8em; }
+.before { background: #9ab; }
+.after { background: #3a7; }
+.graph { overflow: auto; max-height: 40em; border: 1px solid #ccc; margin-bottom: 0.5em; }
+.graph circle { cursor: pointer; }
+"#;
+
+const SCRIPT: &str = r##"
+function drawGraph(name, groups) {
+  const svgNs = "http://www.w3.org/2000/svg";
+  const svg = document.getElementById(name + "-graph");
+  const info = document.getElementById(name + "-info");
+  // Each chain depth is a column, with the groups in id order down it
+  const columns = new Map();
+  const positions = new Map();
+  for (const group of groups) {
+    const column = columns.get(group.depth) || [];
+    column.push(group);
+    columns.set(group.depth, column);
+  }
+  let width = 0, height = 0;
+  for (const [depth, column] of columns) {
+    column.forEach((group, i) => positions.set(group.id, [30 + depth * 50, 20 + i * 22]));
+    width = Math.max(width, 60 + depth * 50);
+    height = Math.max(height, 40 + column.length * 22);
+  }
+  svg.setAttribute("width", width);
+  svg.setAttribute("height", height);
+  const lines = new Map();
+  for (const group of groups) {
+    if (group.prev === null || !positions.has(group.prev)) continue;
+    const [x1, y1] = positions.get(group.id);
+    const [x2, y2] = positions.get(group.prev);
+    const line = document.createElementNS(svgNs, "line");
+    line.setAttribute("x1", x1); line.setAttribute("y1", y1);
+    line.setAttribute("x2", x2); line.setAttribute("y2", y2);
+    line.setAttribute("stroke", "#aaa");
+    svg.appendChild(line);
+    lines.set(group.id, line);
+  }
+  const byId = new Map(groups.map(group => [group.id, group]));
+  let highlighted = [];
+  for (const group of groups) {
+    const [x, y] = positions.get(group.id);
+    const circle = document.createElementNS(svgNs, "circle");
+    circle.setAttribute("cx", x); circle.setAttribute("cy", y);
+    circle.setAttribute("r", 4 + Math.min(6, Math.log2(group.rows + 1)));
+    circle.setAttribute("fill", group.prev === null ? "#58c" : (group.changed ? "#3a7" : "#bbb"));
+    if (group.level_head) { circle.setAttribute("stroke", "red"); circle.setAttribute("stroke-width", 3); }
+    const title = document.createElementNS(svgNs, "title");
+    title.textContent = group.id + " (" + group.rows + " rows)";
+    circle.appendChild(title);
+    circle.addEventListener("click", () => {
+      // Highlight the chain of predecessors from this group to a snapshot
+      highlighted.forEach(line => line.setAttribute("stroke", "#aaa"));
+      highlighted = [];
+      let current = group;
+      while (current && lines.has(current.id)) {
+        lines.get(current.id).setAttribute("stroke", "red");
+        highlighted.push(lines.get(current.id));
+        current = byId.get(current.prev);
+      }
+      info.textContent = "State group " + group.id + ": " + group.rows + " rows, " +
+        (group.prev === null ? "snapshot" : "predecessor " + group.prev + ", depth " + group.depth) +
+        (group.changed ? ", changed" : "") + (group.level_head ? ", level head" : "");
+    });
+    svg.appendChild(circle);
+  }
+}
+"##;
+
+impl RunReport<'_> {
+    /// Writes the report as a self-contained HTML page
+    ///
+    /// # Arguments
+    ///
+    /// * `output`  - Where to write the report to
+    pub fn write_html(&self, output: &mut impl Write) -> io::Result<()> {
+        let before_rows: usize = self.before.values().map(|e| e.state_map.len()).sum();
+        let after_rows: usize = self.after.values().map(|e| e.state_map.len()).sum();
+        let ratio = after_rows as f64 / before_rows.max(1) as f64;
+
+        let room_id = escape_html(self.room_id);
+
+        writeln!(output, "<!DOCTYPE html>")?;
+        writeln!(output, "<html>\n<head>\n<meta charset=\"utf-8\">")?;
+        writeln!(
+            output,
+            "<title>State compression report for {}</title>",
+            room_id
+        )?;
+        writeln!(output, "<style>{}</style>", STYLE)?;
+        writeln!(output, "</head>\n<body>")?;
+        writeln!(output, "<h1>State compression report for {}</h1>", room_id)?;
+
+        writeln!(output, "<h2>Summary</h2>\n<table>")?;
+        let summary = [
+            ("Number of state groups", self.before.len().to_string()),
+            ("Rows before compression", before_rows.to_string()),
+            ("Rows after compression", after_rows.to_string()),
+            (
+                "Rows saved",
+                before_rows.saturating_sub(after_rows).to_string(),
+            ),
+            ("Size after compression", format!("{:.2}%", ratio * 100.)),
+            (
+                "Forced resets due to lacking prev",
+                self.stats.resets_no_suitable_prev.to_string(),
+            ),
+            (
+                "Compressed rows caused by the above",
+                self.stats.resets_no_suitable_prev_size.to_string(),
+            ),
+            (
+                "State groups changed",
+                self.stats.state_groups_changed.to_string(),
+            ),
+            (
+                "State groups with a predecessor in another room",
+                self.cross_room.cross_room_groups.len().to_string(),
+            ),
+            (
+                "State groups skipped due to the above",
+                self.cross_room.groups_skipped.to_string(),
+            ),
+            (
+                "State groups made into snapshots due to the above",
+                self.cross_room.snapshot_groups.len().to_string(),
+            ),
+            (
+                "Snapshots before compression",
+                self.stats.snapshots_before.to_string(),
+            ),
+            (
+                "Snapshots after compression",
+                self.stats.snapshots_after.to_string(),
+            ),
+            (
+                "State groups placed in each level",
+                format!("{:?}", self.stats.groups_per_level),
+            ),
+            (
+                "Times a level head wasn't a valid base for a delta",
+                self.stats.delta_walks.to_string(),
+            ),
+            (
+                "Groups walked up to find a base (in total / at most)",
+                format!(
+                    "{} / {}",
+                    self.stats.delta_walk_steps, self.stats.delta_walk_max_steps
+                ),
+            ),
+            (
+                "Time spent loading",
+                format!("{:.2?}", self.stats.load_time),
+            ),
+            (
+                "Time spent compressing",
+                format!("{:.2?}", self.stats.compress_time),
+            ),
+        ];
+        for (name, value) in summary {
+            writeln!(
+                output,
+                "<tr><td class=\"label\">{}</td><td>{}</td></tr>",
+                name, value
+            )?;
+        }
+        writeln!(output, "</table>")?;
+
+        let before_depths = chain_depths(self.before);
+        let after_depths = chain_depths(self.after);
+
+        self.write_histogram(
+            output,
+            "Chain depths",
+            "Predecessors away from a snapshot",
+            &histogram(
+                &self.stats.chain_depths_before,
+                &self.stats.chain_depths_after,
+            ),
+        )?;
+        self.write_histogram(
+            output,
+            "Delta sizes",
+            "Rows in the state group",
+            &histogram(
+                &self.stats.delta_sizes_before,
+                &self.stats.delta_sizes_after,
+            ),
+        )?;
+
+        self.write_largest_snapshots(output)?;
+
+        writeln!(output, "<h2>Graphs</h2>")?;
+        writeln!(
+            output,
+            concat!(
+                "<p>Each column holds the state groups that are the same number of predecessors",
+                " away from a snapshot. Snapshots are blue, changed groups are green and level",
+                " heads are outlined in red. Click on a state group to highlight its chain.</p>"
+            )
+        )?;
+        writeln!(output, "<script>{}</script>", SCRIPT)?;
+        self.write_graph(output, "before", "Before", self.before, &before_depths)?;
+        self.write_graph(output, "after", "After", self.after, &after_depths)?;
+
+        writeln!(output, "</body>\n</html>")?;
+
+        Ok(())
+    }
+
+    /// Writes a histogram comparing the before and after graphs as a table
+    /// with bars
+    fn write_histogram(
+        &self,
+        output: &mut impl Write,
+        title: &str,
+        label: &str,
+        buckets: &[HistogramBucket],
+    ) -> io::Result<()> {
+        let largest = buckets
+            .iter()
+            .map(|b| b.before.max(b.after))
+            .max()
+            .unwrap_or_default()
+            .max(1);
+
+        writeln!(output, "<h2>{}</h2>\n<table>", title)?;
+        writeln!(
+            output,
+            "<tr><th>{}</th><th>Before</th><th>After</th><th></th></tr>",
+            label
+        )?;
+        for bucket in buckets {
+            writeln!(
+                output,
+                concat!(
+                    "<tr><td class=\"label\">{}</td><td>{}</td><td>{}</td><td class=\"label\">",
+                    "<span class=\"bar before\" style=\"width: {}px\"></span><br>",
+                    "<span class=\"bar after\" style=\"width: {}px\"></span></td></tr>"
+                ),
+                bucket.label,
+                bucket.before,
+                bucket.after,
+                bucket.before * 300 / largest,
+                bucket.after * 300 / largest,
+            )?;
+        }
+        writeln!(output, "</table>")
+    }
+
+    /// Writes a table of the largest snapshots after compression
+    fn write_largest_snapshots(&self, output: &mut impl Write) -> io::Result<()> {
+        let mut snapshots: Vec<(i64, usize)> = self
+            .after
+            .iter()
+            .filter(|(_, entry)| entry.prev_state_group.is_none())
+            .map(|(sg, entry)| (*sg, entry.state_map.len()))
+            .collect();
+        // Largest first, with the smallest id first for equal sizes
+        snapshots.sort_by_key(|(sg, rows)| (std::cmp::Reverse(*rows), *sg));
+
+        writeln!(output, "<h2>Largest snapshots</h2>\n<table>")?;
+        writeln!(
+            output,
+            "<tr><th>State group</th><th>Rows</th><th>Snapshot before compression</th></tr>"
+        )?;
+        for (sg, rows) in snapshots.into_iter().take(LARGEST_SNAPSHOTS) {
+            let was_snapshot = self
+                .before
+                .get(&sg)
+                .is_some_and(|entry| entry.prev_state_group.is_none());
+            writeln!(
+                output,
+                "<tr><td>{}</td><td>{}</td><td>{}</td></tr>",
+                sg,
+                rows,
+                if was_snapshot { "yes" } else { "no" }
+            )?;
+        }
+        writeln!(output, "</table>")
+    }
+
+    /// Writes an SVG element, and the script to draw one of the graphs in it
+    fn write_graph(
+        &self,
+        output: &mut impl Write,
+        name: &str,
+        title: &str,
+        groups: &Graph,
+        depths: &BTreeMap<i64, usize>,
+    ) -> io::Result<()> {
+        writeln!(output, "<h3>{}</h3>", title)?;
+
+        if groups.len() > MAX_DRAWN_GROUPS {
+            return writeln!(
+                output,
+                "<p>Not drawn as there are more than {} state groups.</p>",
+                MAX_DRAWN_GROUPS
+            );
+        }
+
+        let level_heads: Vec<i64> = if name == "after" {
+            self.levels.iter().filter_map(|l| l.get_head()).collect()
+        } else {
+            Vec::new()
+        };
+
+        let drawn: Vec<DrawnGroup> = groups
+            .iter()
+            .map(|(sg, entry)| DrawnGroup {
+                id: *sg,
+                prev: entry.prev_state_group,
+                rows: entry.state_map.len(),
+                depth: depths[sg],
+                changed: self.before.get(sg) != self.after.get(sg),
+                level_head: level_heads.contains(sg),
+            })
+            .collect();
+
+        // The data is just numbers and booleans so can't contain "</script>"
+        let data = serde_json::to_string(&drawn).expect("Serializing groups cannot fail");
+
+        writeln!(
+            output,
+            "<div class=\"graph\"><svg id=\"{}-graph\"></svg></div>",
+            name
+        )?;
+        writeln!(output, "<p id=\"{}-info\"></p>", name)?;
+        writeln!(
+            output,
+            "<script>drawGraph(\"{}\", {});</script>",
+            name, data
+        )
+    }
+}
+
+#[cfg(test)]
+mod report_tests;
@@ -0,0 +1,119 @@
+use crate::{
+    compressor::{Compressor, Histogram, Stats},
+    report::{escape_html, histogram, HistogramBucket, RunReport},
+    CrossRoomStats, StateGroupEntry,
+};
+use state_map::StateMap;
+use std::collections::BTreeMap;
+
+fn bucket(label: &str, before: usize, after: usize) -> HistogramBucket {
+    HistogramBucket {
+        label: label.to_string(),
+        before,
+        after,
+    }
+}
+
+#[test]
+fn histogram_lines_up_before_and_after_buckets() {
+    let buckets = histogram(
+        &Histogram::from_values([0, 1, 2, 3, 9]),
+        &Histogram::from_values([1, 1, 5]),
+    );
+
+    assert_eq!(
+        buckets,
+        vec![
+            bucket("0", 1, 0),
+            bucket("1", 1, 2),
+            bucket("2-3", 2, 0),
+            bucket("4-7", 0, 1),
+            bucket("8-15", 1, 0),
+        ]
+    );
+}
+
+#[test]
+fn histogram_of_nothing_is_empty() {
+    assert_eq!(
+        histogram(&Histogram::default(), &Histogram::default()),
+        Vec::new()
+    );
+}
+
+#[test]
+fn escape_html_escapes_special_characters() {
+    assert_eq!(
+        escape_html("<a href=\"x\">'&'</a>"),
+        "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+    );
+}
+
+#[test]
+fn write_html_includes_summary_and_graphs() {
+    // This is the structure 0-1-2-3-4-5-6, where each group adds a single row
+    let mut initial: BTreeMap<i64, StateGroupEntry> = BTreeMap::new();
+    let mut prev = None;
+    for i in 0i64..=6 {
+        let mut entry = StateGroupEntry {
+            in_range: true,
+            prev_state_group: prev,
+            state_map: StateMap::new(),
+        };
+        entry
+            .state_map
+            .insert("group", &i.to_string(), "seen".into());
+        initial.insert(i, entry);
+        prev = Some(i);
+    }
+
+    let compressor = Compressor::compress(&initial, &[3, 3]);
+    let levels = compressor.get_level_info();
+
+    let report = RunReport {
+        room_id: "!room<1>:example.com",
+        before: &initial,
+        after: &compressor.new_state_group_map,
+        stats: &compressor.stats,
+        cross_room: &CrossRoomStats::default(),
+        levels: &levels,
+    };
+
+    let mut output = Vec::new();
+    report.write_html(&mut output).unwrap();
+    let html = String::from_utf8(output).unwrap();
+
+    assert!(html.starts_with("<!DOCTYPE html>"));
+    assert!(html.contains("<h1>State compression report for !room&lt;1&gt;:example.com</h1>"));
+    assert!(html.contains("<tr><td class=\"label\">Rows before compression</td><td>7</td></tr>"));
+    assert!(html.contains(&format!(
+        "<tr><td class=\"label\">State groups changed</td><td>{}</td></tr>",
+        compressor.stats.state_groups_changed
+    )));
+    assert!(html.contains("drawGraph(\"before\", [{\"id\":0,\"prev\":null,\"rows\":1,\"depth\":0,\"changed\":false,\"level_head\":false}"));
+    assert!(html.contains("drawGraph(\"after\", "));
+    assert!(html.ends_with("</html>\n"));
+}
+
+#[test]
+fn write_html_does_not_draw_huge_graphs() {
+    let groups: BTreeMap<i64, StateGroupEntry> = (0..=5000)
+        .map(|sg| (sg, StateGroupEntry::default()))
+        .collect();
+
+    let report = RunReport {
+        room_id: "room1",
+        before: &groups,
+        after: &groups,
+        stats: &Stats::default(),
+        cross_room: &CrossRoomStats::default(),
+        levels: &[],
+    };
+
+    let mut output = Vec::new();
+    report.write_html(&mut output).unwrap();
+    let html = String::from_utf8(output).unwrap();
+
+    assert!(html.contains("<p>Not drawn as there are more than 5000 state groups.</p>"));
+    assert!(!html.contains("drawGraph(\"before\""));
+}
@@ -0,0 +1,156 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This estimates how many bytes the rows of `state_groups_state` take up,
+//! since rows vary a lot in width depending on the lengths of their type,
+//! state key and event id (so the number of rows saved doesn't say much
+//! about the space saved).
+//!
+//! The size of a row is worked out from its strings in the same way that
+//! Postgres lays it out: a tuple header, the `state_group` column, a length
+//! header for each text column and padding to the next 8 bytes, plus a line
+//! pointer in the page. Each row also has an entry in Synapse's
+//! `(state_group, type, state_key)` index, which is counted in the same way.
+//! This leaves out the free space in pages and dead rows that haven't been
+//! vacuumed, so the sizes are the least space that the rows need.
+
+use std::collections::BTreeMap;
+
+use crate::StateGroupEntry;
+
+/// The size of the header of a heap tuple (which is padded to 24 bytes)
+const TUPLE_HEADER: usize = 24;
+/// The size of the header of an index tuple
+const INDEX_TUPLE_HEADER: usize = 8;
+/// The size of the line pointer to each tuple in a page
+const LINE_POINTER: usize = 4;
+/// The size of a bigint column
+const BIGINT: usize = 8;
+
+/// Estimates the number of bytes that a row of state_groups_state takes up
+/// in the table and its index
+pub fn state_row_bytes(room_id: &str, etype: &str, state_key: &str, event_id: &str) -> usize {
+    let heap = TUPLE_HEADER + BIGINT;
+    let heap = [room_id, etype, state_key, event_id]
+        .iter()
+        .fold(heap, |offset, text| add_text(offset, text));
+
+    let index = INDEX_TUPLE_HEADER + BIGINT;
+    let index = [etype, state_key]
+        .iter()
+        .fold(index, |offset, text| add_text(offset, text));
+
+    LINE_POINTER + align(heap, 8) + LINE_POINTER + align(index, 8)
+}
+
+/// Estimates the number of bytes that the rows of the state groups in the map
+/// take up in state_groups_state (and its index)
+pub fn state_map_bytes(room_id: &str, map: &BTreeMap<i64, StateGroupEntry>) -> usize {
+    map.values()
+        .flat_map(|entry| entry.state_map.iter())
+        .map(|((t, s), e)| state_row_bytes(room_id, t, s, e))
+        .sum()
+}
+
+/// Formats a number of bytes in the largest unit that keeps it above one
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024. && unit < UNITS.len() - 1 {
+        size /= 1024.;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Adds a text column at `offset`, returning the offset after it
+///
+/// Short strings have a 1 byte length header and no alignment, while longer
+/// ones have a 4 byte header and are aligned to 4 bytes
+fn add_text(offset: usize, text: &str) -> usize {
+    if text.len() < 127 {
+        offset + 1 + text.len()
+    } else {
+        align(offset, 4) + 4 + text.len()
+    }
+}
+
+/// Rounds `offset` up to a multiple of `alignment`
+fn align(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use state_map::StateMap;
+
+    #[test]
+    fn rows_are_laid_out_like_postgres() {
+        // Heap: 24 + 8 + (1 + 5) + (1 + 13) + (1 + 0) + (1 + 6) = 60, so 64
+        // Index: 8 + 8 + (1 + 13) + (1 + 0) = 31, so 32
+        assert_eq!(
+            state_row_bytes("room1", "m.room.create", "", "$event"),
+            4 + 64 + 4 + 32
+        );
+
+        // Longer strings have a 4 byte (aligned) header
+        let long_key = "k".repeat(200);
+        // Heap: 24 + 8 + 6 + 14 = 52, + 4 + 200 = 256, + 7 = 263, so 264
+        // Index: 8 + 8 + 14 = 30, aligned to 32, + 4 + 200 = 236, so 240
+        assert_eq!(
+            state_row_bytes("room1", "m.room.member", &long_key, "$event"),
+            4 + 264 + 4 + 240
+        );
+    }
+
+    #[test]
+    fn map_bytes_are_summed_over_every_row() {
+        let mut state_map = StateMap::new();
+        state_map.insert("m.room.create", "", "$event".into());
+        state_map.insert("m.room.create", "", "$event".into());
+        state_map.insert("m.room.name", "", "$other".into());
+
+        let mut map = BTreeMap::new();
+        map.insert(
+            1,
+            StateGroupEntry {
+                in_range: true,
+                prev_state_group: None,
+                state_map,
+            },
+        );
+
+        assert_eq!(
+            state_map_bytes("room1", &map),
+            state_row_bytes("room1", "m.room.create", "", "$event")
+                + state_row_bytes("room1", "m.room.name", "", "$other")
+        );
+    }
+
+    #[test]
+    fn format_bytes_uses_largest_unit() {
+        assert_eq!(format_bytes(0), "0 B");
+        assert_eq!(format_bytes(1023), "1023 B");
+        assert_eq!(format_bytes(1536), "1.5 KiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024), "3.0 GiB");
+    }
+}
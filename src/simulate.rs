@@ -0,0 +1,202 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This compresses the same state groups with several different level sizes
+//! so that they can be compared, without writing anything.
+//!
+//! The level sizes trade the number of rows saved against the number of
+//! predecessors that have to be followed to fetch a group's state (which is
+//! at most the sum of the sizes), so for each configuration the rows after
+//! compression are given along with how deep the chains of predecessors end
+//! up. The room only has to be loaded from the database once for all of
+//! them.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, fmt::Write};
+
+use crate::{chain_depths, compressor::Compressor, row_size, StateGroupEntry, Stats};
+
+/// How the state groups compressed with one configuration of level sizes
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct SimulationResult {
+    // The level sizes that were used
+    pub level_sizes: Vec<usize>,
+    // The number of rows after compression, and compressed_rows / original_rows
+    pub compressed_rows: usize,
+    pub ratio: f64,
+    // The estimated size of the rows after compression (see `row_size`)
+    pub compressed_bytes: usize,
+    // The largest and mean number of predecessors between a state group and
+    // a snapshot after compression
+    pub max_chain_depth: usize,
+    pub mean_chain_depth: f64,
+    // Statistics about how the compression went
+    pub stats: Stats,
+}
+
+/// The results of compressing a room's state groups with each configuration
+/// of level sizes
+#[derive(Serialize, Default, Debug, Clone)]
+pub struct Simulation {
+    pub room_id: String,
+    // The number of state groups that were loaded
+    pub state_groups: usize,
+    // The number of rows (and their estimated size) before compression, and
+    // the largest and mean depths of the chains of predecessors
+    pub original_rows: usize,
+    pub original_bytes: usize,
+    pub max_chain_depth: usize,
+    pub mean_chain_depth: f64,
+    // The results for each configuration, in the order they were given
+    pub results: Vec<SimulationResult>,
+    // Why the simulation stopped before finishing (None if it didn't)
+    pub stopped_early: Option<String>,
+}
+
+impl Simulation {
+    /// Compresses the state groups with each of the configurations of level
+    /// sizes in turn
+    pub fn run(
+        room_id: &str,
+        state_group_map: &BTreeMap<i64, StateGroupEntry>,
+        level_sizes: &[Vec<usize>],
+    ) -> Simulation {
+        let original_rows = state_group_map
+            .values()
+            .map(|entry| entry.state_map.len())
+            .sum();
+        let (max_chain_depth, mean_chain_depth) = depth_stats(state_group_map);
+
+        let results = level_sizes
+            .iter()
+            .map(|sizes| {
+                let mut compressor = Compressor::compress(state_group_map, sizes);
+                let new_state_group_map = &compressor.new_state_group_map;
+
+                let compressed_rows = new_state_group_map
+                    .values()
+                    .map(|entry| entry.state_map.len())
+                    .sum();
+                let (max_chain_depth, mean_chain_depth) = depth_stats(new_state_group_map);
+
+                SimulationResult {
+                    level_sizes: sizes.clone(),
+                    compressed_rows,
+                    // Groups without any rows stay without any rows
+                    ratio: if original_rows == 0 {
+                        1.
+                    } else {
+                        compressed_rows as f64 / original_rows as f64
+                    },
+                    compressed_bytes: row_size::state_map_bytes(room_id, new_state_group_map),
+                    max_chain_depth,
+                    mean_chain_depth,
+                    stats: std::mem::take(&mut compressor.stats),
+                }
+            })
+            .collect();
+
+        Simulation {
+            room_id: room_id.to_string(),
+            state_groups: state_group_map.len(),
+            original_rows,
+            original_bytes: row_size::state_map_bytes(room_id, state_group_map),
+            max_chain_depth,
+            mean_chain_depth,
+            results,
+            stopped_early: None,
+        }
+    }
+
+    /// Formats the results as a table with a row for each configuration,
+    /// starting with the state groups as they were
+    pub fn table(&self) -> String {
+        let labels: Vec<String> = self
+            .results
+            .iter()
+            .map(|result| {
+                let sizes: Vec<String> = result.level_sizes.iter().map(usize::to_string).collect();
+                sizes.join(",")
+            })
+            .collect();
+        let width = labels
+            .iter()
+            .map(String::len)
+            .chain(["Level sizes".len()])
+            .max()
+            .unwrap_or_default();
+
+        let mut table = String::new();
+        writeln!(
+            table,
+            "{:<width$}  {:>12}  {:>8}  {:>12}  {:>9}  {:>10}  {:>9}  {:>9}",
+            "Level sizes",
+            "Rows",
+            "Ratio",
+            "Size",
+            "Max depth",
+            "Mean depth",
+            "Changed",
+            "Snapshots",
+        )
+        .unwrap();
+        writeln!(
+            table,
+            "{:<width$}  {:>12}  {:>8}  {:>12}  {:>9}  {:>10.2}  {:>9}  {:>9}",
+            "(original)",
+            self.original_rows,
+            "100.00%",
+            row_size::format_bytes(self.original_bytes as u64),
+            self.max_chain_depth,
+            self.mean_chain_depth,
+            "-",
+            "-",
+        )
+        .unwrap();
+
+        for (label, result) in labels.iter().zip(&self.results) {
+            writeln!(
+                table,
+                "{:<width$}  {:>12}  {:>7.2}%  {:>12}  {:>9}  {:>10.2}  {:>9}  {:>9}",
+                label,
+                result.compressed_rows,
+                result.ratio * 100.,
+                row_size::format_bytes(result.compressed_bytes as u64),
+                result.max_chain_depth,
+                result.mean_chain_depth,
+                result.stats.state_groups_changed,
+                result.stats.snapshots_after,
+            )
+            .unwrap();
+        }
+
+        table
+    }
+}
+
+/// The largest and mean number of predecessors between the state groups and
+/// a snapshot
+fn depth_stats(groups: &BTreeMap<i64, StateGroupEntry>) -> (usize, f64) {
+    let depths = chain_depths(groups);
+    let max = depths.values().copied().max().unwrap_or(0);
+    let mean = if depths.is_empty() {
+        0.
+    } else {
+        depths.values().sum::<usize>() as f64 / depths.len() as f64
+    };
+    (max, mean)
+}
+
+#[cfg(test)]
+mod simulate_tests;
@@ -0,0 +1,61 @@
+use crate::{simulate::Simulation, test_utils::line_with_state};
+use state_map::StateMap;
+
+#[test]
+fn each_configuration_is_compared_with_the_original() {
+    // 0-1-2-3-4-5-6-7-8-9-10-11-12
+    let map = line_with_state(0, 12);
+
+    let simulation = Simulation::run("room1", &map, &[vec![1], vec![3, 3], vec![100, 50, 25]]);
+
+    assert_eq!(simulation.state_groups, 13);
+    assert_eq!(simulation.original_rows, 26);
+    assert_eq!(simulation.max_chain_depth, 12);
+    assert_eq!(simulation.mean_chain_depth, 6.0);
+    assert_eq!(simulation.results.len(), 3);
+
+    // With a single level of size 1 every group is a snapshot
+    let snapshots = &simulation.results[0];
+    assert_eq!(snapshots.level_sizes, vec![1]);
+    assert_eq!(snapshots.max_chain_depth, 0);
+    assert_eq!(snapshots.mean_chain_depth, 0.0);
+    assert_eq!(snapshots.stats.snapshots_after, 13);
+    // Group i then has i + 2 rows
+    assert_eq!(snapshots.compressed_rows, (2..=14).sum::<usize>());
+    assert!(snapshots.ratio > 1.0);
+
+    // The chains are never longer than the sum of the level sizes
+    let levels = &simulation.results[1];
+    assert_eq!(levels.level_sizes, vec![3, 3]);
+    assert!(levels.max_chain_depth <= 6);
+    assert!(levels.max_chain_depth < simulation.max_chain_depth);
+
+    // Levels bigger than the room leave the chain as it was
+    let large = &simulation.results[2];
+    assert_eq!(large.compressed_rows, 26);
+    assert_eq!(large.max_chain_depth, 12);
+    assert_eq!(large.stats.state_groups_changed, 0);
+
+    let table = simulation.table();
+    let lines: Vec<&str> = table.lines().collect();
+    assert_eq!(lines.len(), 5);
+    assert!(lines[0].starts_with("Level sizes"));
+    assert!(lines[1].starts_with("(original)"));
+    assert!(lines[2].starts_with("1 "));
+    assert!(lines[3].starts_with("3,3 "));
+    assert!(lines[4].starts_with("100,50,25 "));
+}
+
+#[test]
+fn groups_without_rows_have_a_ratio_of_one() {
+    let mut map = line_with_state(0, 2);
+    for entry in map.values_mut() {
+        entry.state_map = StateMap::new();
+    }
+
+    let simulation = Simulation::run("room1", &map, &[vec![3, 3]]);
+
+    assert_eq!(simulation.original_rows, 0);
+    assert_eq!(simulation.results[0].compressed_rows, 0);
+    assert_eq!(simulation.results[0].ratio, 1.0);
+}
@@ -0,0 +1,182 @@
+// Copyright 2018 New Vector Ltd
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! This works out how to connect to the database from Synapse's own config
+//! file (homeserver.yaml), so that the credentials don't have to be copied
+//! into the compressor's command line and it keeps using the same database
+//! as Synapse.
+//!
+//! Synapse passes the `args` of its `database` section straight to psycopg2,
+//! which uses the same names as libpq, so they are turned into a key-value
+//! connection string of the form `user='...' password='...' dbname='...'`.
+//! If the config has a `databases` section instead, the database holding the
+//! `state` data store is used.
+
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::Path};
+
+use crate::config_file::yaml;
+
+/// The parts of homeserver.yaml needed to connect to the database
+#[derive(Deserialize)]
+struct HomeserverConfig {
+    database: Option<DatabaseConfig>,
+    databases: Option<BTreeMap<String, DatabaseConfig>>,
+}
+
+/// A database section of homeserver.yaml
+#[derive(Deserialize)]
+struct DatabaseConfig {
+    name: String,
+    #[serde(default)]
+    args: BTreeMap<String, yaml::Value>,
+    #[serde(default)]
+    data_stores: Vec<String>,
+}
+
+/// The psycopg2 arguments that are used in the connection string, along with
+/// the name they have in it. Any others (such as Synapse's `cp_min` and
+/// `cp_max`) are only used by Synapse so are ignored
+const CONNECTION_ARGS: [(&str, &str); 14] = [
+    ("user", "user"),
+    ("password", "password"),
+    ("database", "dbname"),
+    ("dbname", "dbname"),
+    ("host", "host"),
+    ("port", "port"),
+    ("sslmode", "sslmode"),
+    ("options", "options"),
+    ("application_name", "application_name"),
+    ("connect_timeout", "connect_timeout"),
+    ("keepalives", "keepalives"),
+    ("keepalives_idle", "keepalives_idle"),
+    ("keepalives_interval", "keepalives_interval"),
+    ("target_session_attrs", "target_session_attrs"),
+];
+
+/// Reads the Synapse config file at `path` and returns a connection string
+/// for the database that Synapse keeps its state in
+pub fn db_url_from_synapse_config(path: &Path) -> Result<String, String> {
+    let contents = fs::read_to_string(path)
+        .map_err(|e| format!("Unable to read Synapse config {}: {}", path.display(), e))?;
+
+    db_url_from_yaml(&contents)
+        .map_err(|e| format!("Unable to use Synapse config {}: {}", path.display(), e))
+}
+
+/// Works out the connection string from the contents of a Synapse config file
+fn db_url_from_yaml(contents: &str) -> Result<String, String> {
+    let config: HomeserverConfig = yaml::from_str(contents).map_err(|e| e.to_string())?;
+
+    let database = match (config.databases, config.database) {
+        (Some(databases), _) => databases
+            .into_values()
+            .find(|database| database.data_stores.iter().any(|store| store == "state"))
+            .ok_or("none of the databases hold the state data store")?,
+        (None, Some(database)) => database,
+        // Synapse defaults to SQLite when there isn't a database section
+        (None, None) => return Err("there is no database section".to_string()),
+    };
+
+    if database.name != "psycopg2" {
+        return Err(format!(
+            "the database is {} rather than PostgreSQL (psycopg2)",
+            database.name
+        ));
+    }
+
+    let mut params = Vec::new();
+    for (arg, key) in CONNECTION_ARGS {
+        let value = match database.args.get(arg) {
+            Some(yaml::Value::String(value)) => value.clone(),
+            Some(yaml::Value::Number(value)) => value.to_string(),
+            Some(yaml::Value::Bool(value)) => value.to_string(),
+            Some(yaml::Value::Null) | None => continue,
+            Some(_) => return Err(format!("database argument {} isn't a single value", arg)),
+        };
+        params.push(format!("{}='{}'", key, quote(&value)));
+    }
+
+    Ok(params.join(" "))
+}
+
+/// Escapes a value so that it can be put between single quotes in a key-value
+/// connection string
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('\'', "\\'")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn database_args_become_connection_string() {
+        let url = db_url_from_yaml(concat!(
+            "server_name: example.com\n",
+            "database:\n",
+            "  name: psycopg2\n",
+            "  args:\n",
+            "    user: synapse_user\n",
+            "    password: \"it's a \\\\secret\"\n",
+            "    database: synapse\n",
+            "    host: localhost\n",
+            "    port: 5432\n",
+            "    cp_min: 5\n",
+            "    cp_max: 10\n",
+        ))
+        .unwrap();
+
+        assert_eq!(
+            url,
+            r"user='synapse_user' password='it\'s a \\secret' dbname='synapse' host='localhost' port='5432'"
+        );
+
+        let config: postgres::Config = url.parse().unwrap();
+        assert_eq!(config.get_user(), Some("synapse_user"));
+        assert_eq!(config.get_password(), Some(&b"it's a \\secret"[..]));
+        assert_eq!(config.get_dbname(), Some("synapse"));
+        assert_eq!(config.get_ports(), &[5432]);
+    }
+
+    #[test]
+    fn database_holding_state_is_used() {
+        let url = db_url_from_yaml(concat!(
+            "databases:\n",
+            "  main:\n",
+            "    name: psycopg2\n",
+            "    data_stores: [main]\n",
+            "    args: {database: main_db}\n",
+            "  state:\n",
+            "    name: psycopg2\n",
+            "    data_stores: [state]\n",
+            "    args: {database: state_db}\n",
+        ))
+        .unwrap();
+
+        assert_eq!(url, "dbname='state_db'");
+    }
+
+    #[test]
+    fn sqlite_is_rejected() {
+        assert_eq!(
+            db_url_from_yaml("database:\n  name: sqlite3\n  args: {database: homeserver.db}\n"),
+            Err("the database is sqlite3 rather than PostgreSQL (psycopg2)".to_string())
+        );
+        assert_eq!(
+            db_url_from_yaml("server_name: example.com\n"),
+            Err("there is no database section".to_string())
+        );
+    }
+}
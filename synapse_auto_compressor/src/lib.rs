@@ -63,6 +63,7 @@ mod synapse_auto_compressor {
     use super::*;
     use log::{error, info, LevelFilter};
     use pyo3::exceptions::PyRuntimeError;
+    use synapse_compress_state::{CrossRoomPolicy, VerifyMode};
 
     #[pymodule_init]
     fn init(_m: &Bound<'_, PyModule>) -> PyResult<()> {
@@ -95,14 +96,29 @@ mod synapse_auto_compressor {
         chunk_size, // has no default
         number_of_chunks, // has no default
         default_levels = "100,50,25",
+        verify_after_commit = false,
+        verify_mode = "full",
+        cross_room_policy = "abort",
+        load_connections = 1,
     ))]
+    #[allow(clippy::too_many_arguments)]
     fn run_compression(
         py: Python,
         db_url: &str,
         chunk_size: i64,
         number_of_chunks: i64,
         default_levels: &str,
+        verify_after_commit: bool,
+        verify_mode: &str,
+        cross_room_policy: &str,
+        load_connections: usize,
     ) -> PyResult<()> {
+        if load_connections == 0 {
+            return Err(PyErr::new::<PyRuntimeError, _>(
+                "load_connections must be greater than 0",
+            ));
+        }
+
         // Announce the start of the program to the logs
         info!("synapse_auto_compressor started");
 
@@ -111,6 +127,16 @@ mod synapse_auto_compressor {
             PyErr::new::<PyRuntimeError, _>(format!("Unable to parse level_sizes: {}", e))
         })?;
 
+        // Parse the verify_mode string into a VerifyMode
+        let verify_mode = verify_mode.parse::<VerifyMode>().map_err(|e| {
+            PyErr::new::<PyRuntimeError, _>(format!("Unable to parse verify_mode: {}", e))
+        })?;
+
+        // Parse the cross_room_policy string into a CrossRoomPolicy
+        let cross_room_policy = cross_room_policy.parse::<CrossRoomPolicy>().map_err(|e| {
+            PyErr::new::<PyRuntimeError, _>(format!("Unable to parse cross_room_policy: {}", e))
+        })?;
+
         // Stops the compressor from holding the GIL while running
         py.allow_threads(|| {
             // call compress_chunks_of_database with the arguments supplied
@@ -119,6 +145,10 @@ mod synapse_auto_compressor {
                 chunk_size,
                 &default_levels.0,
                 number_of_chunks,
+                verify_after_commit,
+                verify_mode,
+                cross_room_policy,
+                load_connections,
             )
         })
         .map_err(|e| {
@@ -139,6 +169,16 @@ mod synapse_auto_compressor {
         default_levels: &str,
         number_of_chunks: i64,
     ) -> PyResult<()> {
-        run_compression(py, db_url, chunk_size, number_of_chunks, default_levels)
+        run_compression(
+            py,
+            db_url,
+            chunk_size,
+            number_of_chunks,
+            default_levels,
+            false,
+            "full",
+            "abort",
+            1,
+        )
     }
 }
@@ -20,10 +20,17 @@
 #[global_allocator]
 static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
-use clap::{crate_authors, crate_description, crate_name, crate_version, Arg, Command};
+use clap::{
+    crate_authors, crate_description, crate_name, crate_version, error::ErrorKind,
+    parser::ValueSource, Arg, Command,
+};
 use log::LevelFilter;
-use std::env;
+use std::{env, path::Path};
 use synapse_auto_compressor::{manager, state_saving, LevelInfo};
+use synapse_compress_state::{
+    config_file, connection, row_size::format_bytes, state_table_size, synapse_config,
+    CrossRoomPolicy, VerifyMode,
+};
 
 /// Execution starts here
 fn main() {
@@ -50,8 +57,9 @@ fn main() {
     // Announce the start of the program to the logs
     log::info!("synapse_auto_compressor started");
 
-    // parse the command line arguments using the clap crate
-    let arguments = Command::new(crate_name!())
+    // parse the command line arguments using the clap crate (reading any
+    // options given in a config file with -C first)
+    let mut command = Command::new(crate_name!())
         .version(crate_version!())
         .author(crate_authors!("\n"))
         .about(crate_description!())
@@ -68,7 +76,36 @@ fn main() {
                     "for the full details."
                 ))
                 .num_args(1)
-                .required(true),
+                .required_unless_present("synapse_config")
+                .conflicts_with("synapse_config"),
+        ).arg(
+            Arg::new("synapse_config")
+                .short('Y')
+                .long("synapse-config")
+                .value_name("HOMESERVER_YAML")
+                .help("Connect to the database configured in Synapse's homeserver.yaml")
+                .long_help(concat!(
+                    "Connect to the database configured in Synapse's homeserver.yaml instead of",
+                    " giving -p. The connection parameters are taken from the args of the database",
+                    " section (or of the database holding the state data store if there is a",
+                    " databases section)."
+                ))
+                .num_args(1),
+        ).arg(
+            Arg::new("password_file")
+                .short('w')
+                .long("password-file")
+                .value_name("FILE")
+                .help("Read the database password from a file")
+                .long_help(concat!(
+                    "Read the password for connecting to the database from a file, so that it",
+                    " doesn't need to be put in the connection string (where it can be seen in",
+                    " ps output and shell history). This overrides any password in the connection",
+                    " string. Otherwise any parts missing from the connection string are looked",
+                    " up in the same way as psql does: from the connection service file, the PG*",
+                    " environment variables (such as PGPASSWORD) and the .pgpass file."
+                ))
+                .num_args(1),
         ).arg(
             Arg::new("chunk_size")
                 .short('c')
@@ -117,12 +154,94 @@ fn main() {
                 ))
                 .num_args(1)
                 .required(true),
-        ).get_matches();
+        ).arg(
+            Arg::new("load_connections")
+                .short('j')
+                .value_name("CONNECTIONS")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .help("The number of database connections to load each chunk over in parallel")
+                .long_help(concat!(
+                    "The number of database connections to load the state groups of each chunk over",
+                    " in parallel. The chunk's range of state groups is split up between them, which",
+                    " speeds up loading large chunks at the cost of putting more load on the database."
+                ))
+                .default_value("1")
+                .num_args(1),
+        ).arg(
+            Arg::new("verify_after_commit")
+                .short('v')
+                .action(clap::ArgAction::SetTrue)
+                .help("Check the state in the database after committing each chunk")
+                .long_help(concat!(
+                    "If this flag is set then after each chunk's changes have been committed, the changed",
+                    " state groups are read back from the database and checked to give the same state as",
+                    " before. If they don't, the room is recorded in state_compressor_rooms_needing_attention",
+                    " and skipped by future runs until it is removed from that table."
+                )),
+        ).arg(
+            Arg::new("table_size")
+                .short('z')
+                .long("table-size")
+                .action(clap::ArgAction::SetTrue)
+                .help("Measure the size of state_groups_state on disk before and after the run")
+                .long_help(concat!(
+                    "If this flag is set then the size of the state_groups_state table on disk",
+                    " (including its indexes) is measured with pg_total_relation_size before and",
+                    " after the run. Note that the space used by deleted rows is only reused once",
+                    " the table has been vacuumed, and is only given back to the operating system",
+                    " by VACUUM FULL."
+                )),
+        ).arg(
+            Arg::new("verify_mode")
+                .short('M')
+                .value_name("MODE")
+                .value_parser(clap::value_parser!(VerifyMode))
+                .help("Which state groups to verify the compression of")
+                .long_help(concat!(
+                    "Which state groups to verify the compression of before committing each chunk.",
+                    " This can be \"full\" to check every group, \"incremental\" to only check the",
+                    " groups that have changed or have a changed group in their predecessor chain,",
+                    " or \"sample:PERCENT\" to check roughly PERCENT% of the groups (picked at random",
+                    " from evenly spread out strata) along with every level head and snapshot."
+                ))
+                .default_value("full")
+                .num_args(1),
+        ).arg(
+            Arg::new("cross_room_policy")
+                .short('x')
+                .value_name("POLICY")
+                .value_parser(clap::value_parser!(CrossRoomPolicy))
+                .help("What to do about state groups whose predecessor is in another room")
+                .long_help(concat!(
+                    "What to do about state groups whose predecessor is in another room. This can be",
+                    " \"abort\" to not compress the chunk and record the room in",
+                    " state_compressor_rooms_needing_attention (so that it is skipped by future runs),",
+                    " \"skip\" to leave the groups whose state depends on another room out of the",
+                    " compression, or \"snapshot\" to treat the groups whose predecessor is in another",
+                    " room as snapshots containing their full state."
+                ))
+                .default_value("abort")
+                .num_args(1),
+        );
+    let arguments = config_file::get_matches(command.clone(), &[]);
 
-    // The URL of the database
-    let db_url = arguments
-        .get_one::<String>("postgres-url")
-        .expect("A database url is required");
+    // The URL of the database, which can also be read from Synapse's config
+    // (although -p on the command line takes precedence over a config file)
+    let db_url = match arguments.get_one::<String>("synapse_config") {
+        Some(path) if arguments.value_source("postgres-url") != Some(ValueSource::CommandLine) => {
+            synapse_config::db_url_from_synapse_config(Path::new(path))
+                .unwrap_or_else(|e| command.error(ErrorKind::Io, e).exit())
+        }
+        _ => arguments
+            .get_one::<String>("postgres-url")
+            .cloned()
+            .expect("A database url is required"),
+    };
+    let db_url = match arguments.get_one::<String>("password_file") {
+        Some(path) => connection::add_password_from_file(&db_url, Path::new(path))
+            .unwrap_or_else(|e| command.error(ErrorKind::Io, e).exit()),
+        None => db_url,
+    };
 
     // The number of state groups to work on at once
     let chunk_size = arguments
@@ -142,17 +261,59 @@ fn main() {
         .copied()
         .expect("number_of_chunks is required");
 
+    // The number of connections to load each chunk over
+    let load_connections = arguments
+        .get_one::<u64>("load_connections")
+        .map_or(1, |c| *c as usize);
+
+    // Whether to check the state in the database after committing each chunk
+    let verify_after_commit = arguments.get_flag("verify_after_commit");
+
+    // Whether to measure the size of state_groups_state before and after
+    let table_size = arguments.get_flag("table_size");
+
+    // Which state groups to check before committing each chunk
+    let verify_mode = arguments
+        .get_one::<VerifyMode>("verify_mode")
+        .copied()
+        .unwrap();
+
+    // What to do about state groups whose predecessor is in another room
+    let cross_room_policy = arguments
+        .get_one::<CrossRoomPolicy>("cross_room_policy")
+        .copied()
+        .unwrap();
+
     // Connect to the database and create the 2 tables this tool needs
     // (Note: if they already exist then this does nothing)
-    let mut client = state_saving::connect_to_database(db_url)
-        .unwrap_or_else(|e| panic!("Error occured while connecting to {}: {}", db_url, e));
+    let mut client = state_saving::connect_to_database(&db_url)
+        .unwrap_or_else(|e| panic!("Error occured while connecting to the database: {}", e));
     state_saving::create_tables_if_needed(&mut client)
         .unwrap_or_else(|e| panic!("Error occured while creating tables in database: {}", e));
 
+    let table_size_before = table_size.then(|| state_table_size(&db_url));
+
     // call compress_chunks_of_database with the arguments supplied
     // panic if an error is produced
-    manager::compress_chunks_of_database(db_url, chunk_size, &default_levels.0, number_of_chunks)
-        .unwrap();
+    manager::compress_chunks_of_database(
+        &db_url,
+        chunk_size,
+        &default_levels.0,
+        number_of_chunks,
+        verify_after_commit,
+        verify_mode,
+        cross_room_policy,
+        load_connections,
+    )
+    .unwrap();
+
+    if let Some(before) = table_size_before {
+        log::info!(
+            "Size of state_groups_state on disk before and after: {} / {}",
+            format_bytes(before),
+            format_bytes(state_table_size(&db_url)),
+        );
+    }
 
     log::info!("synapse_auto_compressor finished");
 }
@@ -3,11 +3,14 @@
 
 use crate::state_saving::{
     connect_to_database, create_tables_if_needed, get_next_room_to_compress,
-    read_room_compressor_state, write_room_compressor_state,
+    mark_room_as_needing_attention, read_room_compressor_state, write_room_compressor_state,
 };
 use anyhow::{bail, Context, Result};
-use log::{debug, info, warn};
-use synapse_compress_state::{continue_run, ChunkStats, Level};
+use log::{debug, error, info, warn};
+use synapse_compress_state::{
+    connection::redact_db_url, continue_run, row_size::format_bytes, ChunkStats, CrossRoomPolicy,
+    Level, VerifyMode,
+};
 
 /// Runs the compressor on a chunk of the room
 ///
@@ -36,15 +39,35 @@ use synapse_compress_state::{continue_run, ChunkStats, Level};
 ///                         then we need to provide the compressor with some information
 ///                         on what sort of compression structure we want. The default that
 ///                         the library suggests is `vec![Level::new(100), Level::new(50), Level::new(25)]`
+///
+/// * `verify_after_commit` -   Whether to check the state in the database after the
+///                         changes have been committed. If this check fails then
+///                         the room is marked as needing attention (and so skipped
+///                         by future runs)
+///
+/// * `verify_mode`     -   Which of the compressed state groups to check against the
+///                         original state before committing the changes
+///
+/// * `cross_room_policy` - What to do about state groups whose predecessor is in
+///                         another room. If this is to abort then the room is marked
+///                         as needing attention when any are found
+///
+/// * `load_connections` -  The number of database connections to load the chunk's
+///                         state groups over in parallel
+#[allow(clippy::too_many_arguments)]
 pub fn run_compressor_on_room_chunk(
     db_url: &str,
     room_id: &str,
     chunk_size: i64,
     default_levels: &[Level],
+    verify_after_commit: bool,
+    verify_mode: VerifyMode,
+    cross_room_policy: CrossRoomPolicy,
+    load_connections: usize,
 ) -> Result<Option<ChunkStats>> {
     // connect to the database
-    let mut client =
-        connect_to_database(db_url).with_context(|| format!("Failed to connect to {}", db_url))?;
+    let mut client = connect_to_database(db_url)
+        .with_context(|| format!("Failed to connect to {}", redact_db_url(db_url)))?;
 
     // Access the database to find out where the compressor last got up to
     let retrieved_state = read_room_compressor_state(&mut client, room_id)
@@ -57,7 +80,17 @@ pub fn run_compressor_on_room_chunk(
     };
 
     // run the compressor on this chunk
-    let option_chunk_stats = continue_run(start, chunk_size, db_url, room_id, &level_info);
+    let option_chunk_stats = continue_run(
+        start,
+        chunk_size,
+        db_url,
+        room_id,
+        &level_info,
+        verify_after_commit,
+        verify_mode,
+        cross_room_policy,
+        load_connections,
+    );
 
     if option_chunk_stats.is_none() {
         debug!("No work to do on this room...");
@@ -69,37 +102,85 @@ pub fn run_compressor_on_room_chunk(
 
     debug!("{:?}", chunk_stats);
 
-    // Check to see whether the compressor sent its changes to the database
-    if !chunk_stats.commited {
-        if chunk_stats.new_num_rows - chunk_stats.original_num_rows != 0 {
-            warn!(
-                "The compressor tried to increase the number of rows in {} between {:?} and {}. Skipping...",
-                room_id, start, chunk_stats.last_compressed_group,
-            );
-        }
+    // If the compressed state didn't match the original state then something has
+    // gone badly wrong, so flag the room up instead of skipping over the chunk
+    if !chunk_stats.commited
+        && chunk_stats.groups_committed == 0
+        && chunk_stats.verified == Some(false)
+    {
+        error!(
+            "The compressed state for {} between {:?} and {} doesn't match the original state. Marking room as needing attention...",
+            room_id, start, chunk_stats.last_compressed_group,
+        );
 
-        // Skip over the failed chunk and set the level info to the default (empty) state
-        write_room_compressor_state(
+        mark_room_as_needing_attention(
             &mut client,
             room_id,
-            default_levels,
             chunk_stats.last_compressed_group,
+            "compressed state did not match original state",
         )
-        .with_context(|| {
-            format!(
-                "Failed to skip chunk in room {} between {:?} and {}",
-                room_id, start, chunk_stats.last_compressed_group
-            )
-        })?;
+        .with_context(|| format!("Failed to mark room {} as needing attention", room_id))?;
 
         return Ok(Some(chunk_stats));
     }
 
-    // Save where we got up to after this successful commit
+    // Compressing the chunk would mean using the state of another room, so flag
+    // the room up instead of skipping over the chunk
+    if !chunk_stats.commited
+        && cross_room_policy == CrossRoomPolicy::Abort
+        && !chunk_stats.cross_room.cross_room_groups.is_empty()
+    {
+        error!(
+            "{} state groups in {} between {:?} and {} have a predecessor in another room. Marking room as needing attention...",
+            chunk_stats.cross_room.cross_room_groups.len(),
+            room_id,
+            start,
+            chunk_stats.last_compressed_group,
+        );
+
+        mark_room_as_needing_attention(
+            &mut client,
+            room_id,
+            chunk_stats.last_compressed_group,
+            "state groups have predecessors in another room",
+        )
+        .with_context(|| format!("Failed to mark room {} as needing attention", room_id))?;
+
+        return Ok(Some(chunk_stats));
+    }
+
+    // Check to see whether the compressor sent its changes to the database
+    let new_level_info = if chunk_stats.commited {
+        &chunk_stats.new_level_info
+    } else {
+        if chunk_stats.new_num_rows > chunk_stats.original_num_rows {
+            warn!(
+                "The compressor tried to increase the number of rows in {} between {:?} and {}. Skipping...",
+                room_id, start, chunk_stats.last_compressed_group,
+            );
+        } else if chunk_stats.groups_committed > 0 {
+            warn!(
+                "The state groups in {} between {:?} and {} changed while being compressed, after {} state groups had been committed. Skipping the rest...",
+                room_id, start, chunk_stats.last_compressed_group, chunk_stats.groups_committed,
+            );
+        } else {
+            warn!(
+                "The state groups in {} between {:?} and {} changed while being compressed. Skipping...",
+                room_id, start, chunk_stats.last_compressed_group,
+            );
+        }
+
+        // Skip over the failed chunk and set the level info to the default
+        // (empty) state, as the levels describe a structure that isn't (all)
+        // in the database
+        default_levels
+    };
+
+    // Save where we got up to
     write_room_compressor_state(
         &mut client,
         room_id,
-        &chunk_stats.new_level_info,
+        new_level_info,
         chunk_stats.last_compressed_group,
     )
     .with_context(|| {
@@ -109,6 +190,24 @@ pub fn run_compressor_on_room_chunk(
         )
     })?;
 
+    // If the state in the database no longer matches then flag the room up so
+    // that someone can look at it (and it isn't compressed any further until then).
+    // This includes the groups committed before committing stopped early
+    if chunk_stats.verified == Some(false) {
+        error!(
+            "The state in the database for {} between {:?} and {} doesn't match the original state after compressing. Marking room as needing attention...",
+            room_id, start, chunk_stats.last_compressed_group,
+        );
+
+        mark_room_as_needing_attention(
+            &mut client,
+            room_id,
+            chunk_stats.last_compressed_group,
+            "state in database did not match original state after committing changes",
+        )
+        .with_context(|| format!("Failed to mark room {} as needing attention", room_id))?;
+    }
+
     Ok(Some(chunk_stats))
 }
 
@@ -134,20 +233,39 @@ pub fn run_compressor_on_room_chunk(
 ///
 /// * `number_of_chunks`-   The number of chunks to compress. The larger this number is, the longer
 ///                         the compressor will run for.
+///
+/// * `verify_after_commit` -   Whether to check the state in the database after each chunk's
+///                         changes have been committed, marking the room as needing attention
+///                         if the check fails
+///
+/// * `verify_mode`     -   Which of the compressed state groups to check against the
+///                         original state before committing each chunk's changes
+///
+/// * `cross_room_policy` - What to do about state groups whose predecessor is in
+///                         another room
+///
+/// * `load_connections` -  The number of database connections to load each chunk's
+///                         state groups over in parallel
+#[allow(clippy::too_many_arguments)]
 pub fn compress_chunks_of_database(
     db_url: &str,
     chunk_size: i64,
     default_levels: &[Level],
     number_of_chunks: i64,
+    verify_after_commit: bool,
+    verify_mode: VerifyMode,
+    cross_room_policy: CrossRoomPolicy,
+    load_connections: usize,
 ) -> Result<()> {
     // connect to the database
     let mut client = connect_to_database(db_url)
-        .with_context(|| format!("Failed to connect to database at {}", db_url))?;
+        .with_context(|| format!("Failed to connect to database at {}", redact_db_url(db_url)))?;
 
     create_tables_if_needed(&mut client).context("Failed to create state compressor tables")?;
 
     let mut skipped_chunks = 0;
     let mut rows_saved = 0;
+    let mut bytes_saved = 0;
     let mut chunks_processed = 0;
 
     while chunks_processed < number_of_chunks {
@@ -166,14 +284,31 @@ pub fn compress_chunks_of_database(
             room_to_compress, chunk_size
         );
 
-        let work_done =
-            run_compressor_on_room_chunk(db_url, &room_to_compress, chunk_size, default_levels)?;
+        let work_done = run_compressor_on_room_chunk(
+            db_url,
+            &room_to_compress,
+            chunk_size,
+            default_levels,
+            verify_after_commit,
+            verify_mode,
+            cross_room_policy,
+            load_connections,
+        )?;
 
         if let Some(ref chunk_stats) = work_done {
             if chunk_stats.commited {
                 let savings = chunk_stats.original_num_rows - chunk_stats.new_num_rows;
-                rows_saved += chunk_stats.original_num_rows - chunk_stats.new_num_rows;
-                debug!("Saved {} rows for room {}", savings, room_to_compress);
+                let byte_savings = chunk_stats
+                    .original_num_bytes
+                    .saturating_sub(chunk_stats.new_num_bytes);
+                rows_saved += savings;
+                bytes_saved += byte_savings;
+                debug!(
+                    "Saved {} rows (about {}) for room {}",
+                    savings,
+                    format_bytes(byte_savings as u64),
+                    room_to_compress
+                );
             } else {
                 skipped_chunks += 1;
                 debug!(
@@ -187,8 +322,11 @@ pub fn compress_chunks_of_database(
         }
     }
     info!(
-        "Finished running compressor. Saved {} rows. Skipped {}/{} chunks",
-        rows_saved, skipped_chunks, chunks_processed
+        "Finished running compressor. Saved {} rows (about {}). Skipped {}/{} chunks",
+        rows_saved,
+        format_bytes(bytes_saved as u64),
+        skipped_chunks,
+        chunks_processed
     );
     Ok(())
 }
@@ -2,7 +2,7 @@
 
 use anyhow::{bail, Result};
 use log::trace;
-use synapse_compress_state::Level;
+use synapse_compress_state::{connection, Level};
 
 use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
 use postgres::{fallible_iterator::FallibleIterator, types::ToSql, Client};
@@ -19,11 +19,15 @@ pub fn connect_to_database(db_url: &str) -> Result<Client> {
     builder.set_verify(SslVerifyMode::NONE);
     let connector = MakeTlsConnector::new(builder.build());
 
-    let client = Client::connect(db_url, connector)?;
+    // Fill in anything missing from the URL from the environment, .pgpass etc.
+    let db_url = connection::resolve_db_url(db_url).map_err(anyhow::Error::msg)?;
+
+    let client = Client::connect(&db_url, connector)?;
     Ok(client)
 }
 
-/// Creates the state_compressor_state and state_compressor progress tables
+/// Creates the state_compressor_state, state_compressor_progress and
+/// state_compressor_rooms_needing_attention tables
 ///
 /// If these tables already exist then this function does nothing
 ///
@@ -70,6 +74,15 @@ pub fn create_tables_if_needed(client: &mut Client) -> Result<()> {
 
     client.batch_execute(create_compressor_global_progress_table)?;
 
+    let create_rooms_needing_attention_table = r#"
+        CREATE TABLE IF NOT EXISTS state_compressor_rooms_needing_attention (
+            room_id TEXT PRIMARY KEY,
+            last_compressed BIGINT NOT NULL,
+            reason TEXT NOT NULL
+        )"#;
+
+    client.execute(create_rooms_needing_attention_table, &[])?;
+
     Ok(())
 }
 
@@ -265,10 +278,43 @@ pub fn write_room_compressor_state(
     Ok(())
 }
 
+/// Marks a room as needing attention, so that it is skipped by future runs of
+/// the compressor until the entry is removed from the
+/// `state_compressor_rooms_needing_attention` table
+///
+/// # Arguments
+///
+/// * `client`            - A postgres client used to send the requests to the database
+/// * `room_id`           - The room that needs attention
+/// * `last_compressed`   - The last state_group that was compressed in the room
+/// * `reason`            - Why the room needs attention
+pub fn mark_room_as_needing_attention(
+    client: &mut Client,
+    room_id: &str,
+    last_compressed: i64,
+    reason: &str,
+) -> Result<()> {
+    let params: Vec<&(dyn ToSql + Sync)> = vec![&room_id, &last_compressed, &reason];
+    client.execute(
+        r#"
+            INSERT INTO state_compressor_rooms_needing_attention (room_id, last_compressed, reason)
+                VALUES ($1, $2, $3)
+            ON CONFLICT (room_id)
+                DO UPDATE SET
+                    last_compressed = excluded.last_compressed,
+                    reason = excluded.reason;
+        "#,
+        &params,
+    )?;
+
+    Ok(())
+}
+
 /// Returns the room with with the lowest uncompressed state group id
 ///
 /// A group is detected as uncompressed if it is greater than the `last_compressed`
-/// entry in `state_compressor_progress` for that room.
+/// entry in `state_compressor_progress` for that room. Rooms that have been
+/// marked as needing attention are skipped.
 ///
 /// The `lowest_uncompressed_group` value stored in `state_compressor_total_progress`
 /// stores where this method last finished, to prevent repeating work
@@ -288,6 +334,7 @@ pub fn get_next_room_to_compress(client: &mut Client) -> Result<Option<String>>
                 id > last_compressed
                 OR last_compressed IS NULL
             )
+            AND room_id NOT IN (SELECT room_id FROM state_compressor_rooms_needing_attention)
         ORDER BY id ASC
         LIMIT 1
     "#;